@@ -1,14 +1,16 @@
 use std::collections::BTreeMap;
 use std::collections::BTreeSet;
 
-use serde_derive::Deserialize;
+use serde_derive::{Deserialize, Serialize};
 
+use crate::cache::*;
 use crate::cmd::*;
 use crate::error::*;
 use crate::flake::*;
+use crate::installed;
 
 /// Locally available packages with available 'pname' and 'version' attributes.
-#[derive(Eq, PartialEq, Ord, PartialOrd, Debug)]
+#[derive(Eq, PartialEq, Ord, PartialOrd, Debug, Serialize, Deserialize)]
 pub(crate) struct Package {
     pub(crate) attribute: String,
     pub(crate) name: String,
@@ -16,10 +18,144 @@ pub(crate) struct Package {
     pub(crate) version: String,
 }
 
-/// Returns list of all available packages in parsed form.
-pub(crate) fn get_packages(
-    nixpkgs: &Option<String>,
+/// Flake input as reported by 'nix flake archive --json'.
+#[derive(Deserialize, Debug)]
+struct Input {
+    path: String,
+    #[serde(default)]
+    inputs: BTreeMap<String, Input>,
+}
+
+/// Returns true if 'path' is a full nixpkgs tree 'nix-env -qa' can
+/// query. Lib-only inputs like flake-parts' 'nixpkgs-lib' are not.
+fn has_packages_config(path: &str) -> bool {
+    std::path::Path::new(path)
+        .join("pkgs/top-level/packages-config.nix")
+        .is_file()
+}
+
+/// Collects store paths of all inputs that look like 'nixpkgs'
+/// ('nixpkgs', 'nixpkgs-unstable', ...) including inputs of inputs.
+/// Only paths accepted by 'is_nixpkgs' are kept. Followed inputs share
+/// a path and are collected once.
+fn collect_nixpkgs_inputs(
+    inputs: &BTreeMap<String, Input>,
+    is_nixpkgs: &dyn Fn(&str) -> bool,
+    paths: &mut BTreeSet<String>,
+) {
+    for (iname, i) in inputs {
+        if iname.starts_with("nixpkgs") && !paths.contains(&i.path) {
+            match is_nixpkgs(&i.path) {
+                true => {
+                    paths.insert(i.path.clone());
+                }
+                false => log::debug!("Skipping {iname} input {}: not a nixpkgs tree", i.path),
+            }
+        }
+        collect_nixpkgs_inputs(&i.inputs, is_nixpkgs, paths);
+    }
+}
+
+/// Returns inputs of a system flake as reported by 'nix flake archive'.
+/// 'None' if the system is not flake-based.
+fn get_flake_inputs(
+    nixos_flake: &Flake,
+    ctx: &CmdContext,
+) -> Result<Option<BTreeMap<String, Input>>, OldeError> {
+    let flake_path = nixos_flake.path();
+    let r = nix_cmd(ctx).and_then(|mut c| {
+        c.extend_from_slice(&["flake", "archive", &flake_path, "--json"]);
+        run_cmd(&c, ctx)
+    });
+    // { "path": "/nix/store/...-source",
+    //   "inputs": { "nixpkgs": {
+    //                 "inputs": {},
+    //                 "path": "/nix/store/2z...-source"
+    //               },
+    //               "home-manager": {
+    //                 "inputs": { "nixpkgs": { ... } },
+    //                 "path": "/nix/store/8a...-source"
+    //               }
+    // }
+    match r {
+        Err(e @ OldeError::Canceled(_)) => Err(e),
+        Err(_) => {
+            log::debug!("Failed to fetch flake archive. Not a flake based system?");
+            Ok(None)
+        }
+        Ok(p_u8) => {
+            #[derive(Deserialize, Debug)]
+            struct Archive {
+                inputs: BTreeMap<String, Input>,
+            }
+
+            let prefetched: Archive = serde_json::from_slice(p_u8.as_slice())?;
+            Ok(Some(prefetched.inputs))
+        }
+    }
+}
+
+/// Returns store paths of all 'nixpkgs' inputs of a system flake.
+/// Empty if the system is not flake-based.
+fn get_flake_nixpkgs(nixos_flake: &Flake, ctx: &CmdContext) -> Result<BTreeSet<String>, OldeError> {
+    let mut paths = BTreeSet::new();
+    if let Some(inputs) = get_flake_inputs(nixos_flake, ctx)? {
+        collect_nixpkgs_inputs(&inputs, &has_packages_config, &mut paths);
+        log::debug!("Found nixpkgs flake inputs: {paths:?}");
+    }
+    Ok(paths)
+}
+
+/// Returns store path of 'nixpkgs' used by the system: top-level
+/// 'nixpkgs' flake input or '<nixpkgs>' for non-flake systems.
+pub(crate) fn get_system_nixpkgs(
     nixos_flake: &Flake,
+    ctx: &CmdContext,
+) -> Result<String, OldeError> {
+    if let Some(inputs) = get_flake_inputs(nixos_flake, ctx)? {
+        if let Some(i) = inputs.get("nixpkgs") {
+            return Ok(i.path.clone());
+        }
+    }
+    let out_u8 = run_cmd(&["nix-instantiate", "--find-file", "nixpkgs"], ctx)?;
+    Ok(String::from_utf8(out_u8)?.trim().to_string())
+}
+
+/// 'config' of 'nix-env -qa' query. The same one is used by nixpkgs
+/// to generate 'packages.json.br' for repology.
+const DEFAULT_PACKAGES_CONFIG: &str = "import <nixpkgs/pkgs/top-level/packages-config.nix>";
+
+/// Extra arguments of 'nix-env -qa' query like 'config'.
+#[derive(Default)]
+pub(crate) struct NixArgs {
+    /// 'config' expression instead of 'DEFAULT_PACKAGES_CONFIG'.
+    pub(crate) packages_config: Option<String>,
+    /// '--arg <name> <nix expression>' pairs.
+    pub(crate) args: Vec<(String, String)>,
+    /// '--argstr <name> <string>' pairs.
+    pub(crate) argstrs: Vec<(String, String)>,
+}
+
+impl NixArgs {
+    fn is_empty(&self) -> bool {
+        self.packages_config.is_none() && self.args.is_empty() && self.argstrs.is_empty()
+    }
+
+    fn has(&self, name: &str) -> bool {
+        self.args
+            .iter()
+            .chain(&self.argstrs)
+            .any(|(n, _)| n == name)
+    }
+}
+
+/// Returns packages available in 'nixpkgs' path for 'system'
+/// platform. 'None' stands for default '<nixpkgs>' and local platform.
+fn query_packages(
+    nixpkgs: Option<&str>,
+    system: Option<&str>,
+    nix_args: &NixArgs,
+    ctx: &CmdContext,
 ) -> Result<BTreeSet<Package>, OldeError> {
     // Actual command is taken from pkgs/top-level/make-tarball.nix for
     // 'packages.json.br' build. It's used by repology as is.
@@ -27,79 +163,34 @@ pub(crate) fn get_packages(
         "nix-env",
         "-qa",
         "--json",
-        "--arg",
-        "config",
-        "import <nixpkgs/pkgs/top-level/packages-config.nix>",
         "--option",
         "build-users-group",
         "\"\"",
     ];
-    let mut a = String::new();
+    // User's 'config' (like '{ allowUnfree = true; }') replaces the
+    // default one.
+    if !nix_args.has("config") {
+        let config = nix_args
+            .packages_config
+            .as_deref()
+            .unwrap_or(DEFAULT_PACKAGES_CONFIG);
+        cmd.extend_from_slice(&["--arg", "config", config]);
+    }
+    for (n, v) in &nix_args.args {
+        cmd.extend_from_slice(&["--arg", n, v]);
+    }
+    for (n, v) in &nix_args.argstrs {
+        cmd.extend_from_slice(&["--argstr", n, v]);
+    }
     let na: String;
-    match nixpkgs {
-        None => {
-            // In Nixos without flakes `nix-env` should Just Work.
-            // But in system with flakes we need to extract `nixpkgs`
-            // input and explicitly pass it in. If it fails we just
-            // leave things as is.
-            let r = run_cmd(&[
-                "nix",
-                "--extra-experimental-features",
-                "nix-command",
-                "--extra-experimental-features",
-                "flakes",
-                "flake",
-                "archive",
-                nixos_flake.path().as_str(),
-                "--json",
-            ]);
-            // Assume simplest form:
-            // { "inputs": { "nixpkgs": {
-            //                 "inputs": {},
-            //                 "path": "/nix/store/2z...-source"
-            //             }
-            match r {
-                Err(_) => {
-                    log::debug!("Failed to fetch flake archive. Not a flake based system?");
-                }
-                Ok(p_u8) => {
-                    #[derive(Deserialize, Debug)]
-                    struct Input {
-                        path: String,
-                    }
-                    #[derive(Deserialize, Debug)]
-                    struct Archive {
-                        inputs: BTreeMap<String, Input>,
-                    }
-
-                    let prefetched: Archive = serde_json::from_slice(p_u8.as_slice())?;
-
-                    // TODO: instead of using last in the list consider
-                    // instantiating each of nixkogs inputs (and
-                    // recurse into inputs' inputs). That way we should
-                    // be able to match all possible packages.
-                    for (iname, i) in prefetched.inputs {
-                        if iname == "nixpkgs" {
-                            a = i.path
-                        }
-                    }
-
-                    if !a.is_empty() {
-                        // Assuming flake-based system.
-                        na = format!("nixpkgs={a}");
-                        cmd.extend_from_slice(&["-I", &na]);
-                        cmd.extend_from_slice(&["-f", &a]);
-                    }
-                }
-            }
-        }
-        Some(p) => {
-            na = format!("nixpkgs={p}");
-            cmd.extend_from_slice(&["-I", &na]);
-            cmd.extend_from_slice(&["-f", p]);
-        }
+    if let Some(p) = nixpkgs {
+        na = format!("nixpkgs={p}");
+        cmd.extend_from_slice(&["-I", &na]);
+        cmd.extend_from_slice(&["-f", p]);
+    }
+    if let Some(s) = system {
+        cmd.extend_from_slice(&["--argstr", "system", s]);
     }
-    let ps_u8 = run_cmd(&cmd)?;
     // "nixos.python310Packages.networkx": {
     //   "name": "python3.10-networkx-2.8.6",
     //   "pname": "python3.10-networkx",
@@ -109,23 +200,124 @@ pub(crate) fn get_packages(
     #[derive(Deserialize, Debug)]
     struct Available {
         name: String,
-        pname: String,
+        pname: Option<String>,
         version: String,
     }
 
-    let ps: BTreeMap<String, Available> = serde_json::from_slice(ps_u8.as_slice())?;
+    // Output of full nixpkgs is tens of megabytes: parse it as it
+    // arrives.
+    let ps: BTreeMap<String, Available> =
+        run_cmd_streaming(&cmd, ctx, |r| Ok(serde_json::from_reader(r)?))?;
 
-    let r: BTreeSet<_> = ps
+    Ok(ps
         .iter()
         .map(|(attr, a)| {
+            let pname = match &a.pname {
+                Some(pn) => pn.clone(),
+                // Packages without 'pname' still get matched against
+                // repology by their 'name' without '-version' suffix.
+                None => {
+                    let pn = a
+                        .name
+                        .strip_suffix(&format!("-{}", a.version))
+                        .unwrap_or(&a.name);
+                    log::debug!("No 'pname' for {attr}, derived {pn:?} from {:?}", a.name);
+                    pn.to_string()
+                }
+            };
             Package {
                 attribute: attr.clone(),
                 name: a.name.clone(),
-                pname: a.pname.clone(),
+                pname,
                 version: a.version.clone(),
             }
         })
-        .collect();
+        .collect())
+}
+
+/// Returns packages available in 'nixpkgs' path. Store paths are
+/// immutable: their query results are reused from 'cache' if present.
+fn query_packages_cached(
+    nixpkgs: Option<&str>,
+    system: Option<&str>,
+    nix_args: &NixArgs,
+    cache: Option<&Cache>,
+    ctx: &CmdContext,
+) -> Result<BTreeSet<Package>, OldeError> {
+    // Extra arguments change the result and are not cached.
+    let store_name = nixpkgs
+        .and_then(|p| p.strip_prefix("/nix/store/"))
+        .filter(|n| !n.contains('/') && nix_args.is_empty());
+    let (cache, cache_key) = match (cache, store_name) {
+        (Some(c), Some(n)) => match system {
+            Some(s) => (c, format!("available-{s}-{n}")),
+            None => (c, format!("available-{n}")),
+        },
+        _ => return query_packages(nixpkgs, system, nix_args, ctx),
+    };
+
+    if let Some(r) = cache.get_immutable_json(&cache_key) {
+        log::info!("Using cached available packages of {nixpkgs:?}");
+        return Ok(r);
+    }
+    let r = query_packages(nixpkgs, system, nix_args, ctx)?;
+    cache.put_json(&cache_key, &r)?;
+    Ok(r)
+}
+
+/// Returns list of all available packages in parsed form.
+pub(crate) fn get_packages(
+    nixpkgs: &Option<String>,
+    nixos_flakes: &[Flake],
+    nix_args: &NixArgs,
+    cache: Option<&Cache>,
+    ctx: &CmdContext,
+) -> Result<BTreeSet<Package>, OldeError> {
+    let sources: Vec<Option<String>> = match nixpkgs {
+        Some(p) => vec![Some(p.clone())],
+        None => {
+            // In Nixos without flakes `nix-env` should Just Work.
+            // But in system with flakes we need to extract all
+            // `nixpkgs` inputs and explicitly pass them in. If it
+            // fails we just leave things as is. Inputs of all system
+            // flakes are merged.
+            let mut paths = BTreeSet::new();
+            for f in nixos_flakes {
+                paths.extend(get_flake_nixpkgs(f, ctx)?);
+            }
+            if paths.is_empty() {
+                vec![None]
+            } else {
+                paths.into_iter().map(Some).collect()
+            }
+        }
+    };
+
+    // Identical packages from multiple sources are deduplicated. A
+    // failed source out of many is skipped.
+    let mut r: BTreeSet<Package> = BTreeSet::new();
+    let mut errs: Vec<OldeError> = Vec::new();
+    for source in &sources {
+        let res = query_packages_cached(
+            source.as_deref(),
+            nixos_flakes.first().and_then(|f| f.system()),
+            nix_args,
+            cache,
+            ctx,
+        );
+        match res {
+            Ok(ps) => r.extend(ps),
+            Err(e @ (OldeError::Canceled(_) | OldeError::DryRun(_))) => return Err(e),
+            Err(e) if sources.len() > 1 => {
+                log::warn!("Skipping available packages of {source:?}: {e}");
+                errs.push(e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    if r.is_empty() && !errs.is_empty() {
+        return Err(OldeError::MultipleErrors(errs));
+    }
 
     // Misconfigured nixpkgs, not a NixOS or flake-based system?
     if r.is_empty() {
@@ -134,3 +326,97 @@ pub(crate) fn get_packages(
 
     Ok(r)
 }
+
+/// Turns installed packages into available ones for '--no-available'.
+/// 'pname' is derived from 'name' the same way 'nix-env -qa' does it.
+/// Attributes are unknown and are replaced with package names.
+pub(crate) fn from_installed(ps: &BTreeSet<installed::Package>) -> BTreeSet<Package> {
+    ps.iter()
+        .map(|p| {
+            let pname = match installed::parse_drv_name(&p.name) {
+                Some((pn, _)) => pn.to_string(),
+                None => p.pname.clone().unwrap_or_else(|| p.name.clone()),
+            };
+            Package {
+                attribute: p.name.clone(),
+                name: p.name.clone(),
+                pname,
+                version: p.version.clone(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inputs(json: &str) -> BTreeMap<String, Input> {
+        serde_json::from_str(json).expect("valid inputs")
+    }
+
+    #[test]
+    fn collects_nested_nixpkgs_inputs() {
+        let is = inputs(
+            r#"{
+                "nixpkgs": { "path": "/nix/store/a-source" },
+                "nixpkgs-stable": { "path": "/nix/store/b-source" },
+                "home-manager": {
+                    "path": "/nix/store/c-source",
+                    "inputs": { "nixpkgs": { "path": "/nix/store/d-source" } }
+                }
+            }"#,
+        );
+        let mut paths = BTreeSet::new();
+        collect_nixpkgs_inputs(&is, &|_| true, &mut paths);
+        assert_eq!(
+            paths,
+            BTreeSet::from([
+                "/nix/store/a-source".to_string(),
+                "/nix/store/b-source".to_string(),
+                "/nix/store/d-source".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn skips_lib_only_and_followed_inputs() {
+        let is = inputs(
+            r#"{
+                "nixpkgs": { "path": "/nix/store/a-source" },
+                "flake-parts": {
+                    "path": "/nix/store/c-source",
+                    "inputs": {
+                        "nixpkgs-lib": { "path": "/nix/store/lib-source" },
+                        "nixpkgs": { "path": "/nix/store/a-source" }
+                    }
+                }
+            }"#,
+        );
+        let mut checked = std::cell::RefCell::new(Vec::new());
+        let is_nixpkgs = |p: &str| {
+            checked.borrow_mut().push(p.to_string());
+            !p.contains("lib")
+        };
+        let mut paths = BTreeSet::new();
+        collect_nixpkgs_inputs(&is, &is_nixpkgs, &mut paths);
+        assert_eq!(paths, BTreeSet::from(["/nix/store/a-source".to_string()]));
+        // Followed input is checked only once.
+        assert_eq!(
+            checked.get_mut().as_slice(),
+            ["/nix/store/a-source", "/nix/store/lib-source"]
+        );
+    }
+
+    #[test]
+    fn packages_config_is_required() {
+        let dir = std::env::temp_dir().join(format!("nix-olde-test-{}", std::process::id()));
+        let config_dir = dir.join("pkgs/top-level");
+        std::fs::create_dir_all(&config_dir).expect("test dir");
+        let path = dir.to_str().expect("utf-8 path");
+        assert!(!has_packages_config(path));
+        std::fs::write(config_dir.join("packages-config.nix"), "{}").expect("test file");
+        assert!(has_packages_config(path));
+        std::fs::remove_dir_all(&dir).expect("cleanup");
+    }
+}
@@ -0,0 +1,126 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::error::*;
+
+/// On-disk cache of fetched or evaluated data.
+pub(crate) struct Cache {
+    /// Directory to store cache entries in.
+    dir: PathBuf,
+    /// Entries older than 'ttl' are considered stale and are ignored.
+    ttl: Duration,
+}
+
+/// Turns arbitrary key into a safe file name. Example:
+///     "python:networkx/" -> "python%3Anetworkx%2F"
+fn escape_key(key: &str) -> String {
+    key.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '_' | '-' => c.to_string(),
+            _ => {
+                let mut buf = [0u8; 4];
+                c.encode_utf8(&mut buf)
+                    .bytes()
+                    .map(|b| format!("%{b:02X}"))
+                    .collect()
+            }
+        })
+        .collect()
+}
+
+/// Returns a short stable hash of 'key' to tell apart entries of
+/// similar keys, like fetches of different repology mirrors. FNV-1a
+/// truncated to 32 bits.
+pub(crate) fn short_hash(key: &str) -> String {
+    let h = key.bytes().fold(0xcbf29ce484222325u64, |h, b| {
+        (h ^ u64::from(b)).wrapping_mul(0x100000001b3)
+    });
+    format!("{:08x}", h >> 32)
+}
+
+impl Cache {
+    pub(crate) fn new(dir: &str, ttl: Duration) -> Cache {
+        Cache {
+            dir: PathBuf::from(dir),
+            ttl,
+        }
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.dir.join(escape_key(key) + ".json")
+    }
+
+    /// Returns cached entry contents if it exists and is fresh enough.
+    pub(crate) fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let path = self.entry_path(key);
+        let age = std::fs::metadata(&path).and_then(|m| m.modified()).ok()?;
+        // Entries from the future are fine too.
+        if age.elapsed().unwrap_or_default() > self.ttl {
+            log::debug!("Cache entry {path:?} is stale");
+            return None;
+        }
+        Self::read(&path)
+    }
+
+    /// Returns deserialized entry regardless of its age. Meant for
+    /// keys derived from immutable inputs like store paths. Malformed
+    /// entries (like ones written by other versions) are ignored.
+    pub(crate) fn get_immutable_json<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let c = Self::read(&self.entry_path(key))?;
+        match serde_json::from_slice(&c) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                log::debug!("Ignoring malformed cache entry {key:?}: {e}");
+                None
+            }
+        }
+    }
+
+    fn read(path: &Path) -> Option<Vec<u8>> {
+        match std::fs::read(path) {
+            Err(e) => {
+                log::debug!("Failed to read cache entry {path:?}: {e}");
+                None
+            }
+            Ok(c) => {
+                log::debug!("Using cache entry {path:?}");
+                Some(c)
+            }
+        }
+    }
+
+    /// Stores new contents for an entry.
+    pub(crate) fn put(&self, key: &str, contents: &[u8]) -> Result<(), OldeError> {
+        std::fs::create_dir_all(&self.dir)?;
+        // Write via a temporary file to never expose partial entries
+        // to concurrent readers.
+        let path = self.entry_path(key);
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, contents)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// Stores serialized value for an entry.
+    pub(crate) fn put_json<T: Serialize>(&self, key: &str, value: &T) -> Result<(), OldeError> {
+        self.put(key, &serde_json::to_vec(value)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_hash_is_stable() {
+        // FNV-1a of "a" is 0xaf63dc4c8601ec8c.
+        assert_eq!(short_hash("a"), "af63dc4c");
+        assert_ne!(
+            short_hash("https://repology.org/api/v1/projects/"),
+            short_hash("https://mirror.example/api/v1/projects/")
+        );
+    }
+}
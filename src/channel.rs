@@ -0,0 +1,203 @@
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+use serde_derive::Deserialize;
+
+use crate::available;
+use crate::cmd::*;
+use crate::error::*;
+use crate::flake::*;
+
+/// Repology repository of 'nixpkgs-unstable' and 'master'.
+pub(crate) const UNSTABLE_REPO: &str = "nix_unstable";
+
+/// Returns current (year, month) in UTC.
+fn current_year_month() -> (u32, u32) {
+    let secs = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (year, month, _) = civil_date(secs);
+    (year, month)
+}
+
+/// Converts UNIX timestamp into (year, month, day) in UTC.
+fn civil_date(secs: u64) -> (u32, u32, u32) {
+    // Days to civil date conversion from
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = (secs / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year as u32, month as u32, day as u32)
+}
+
+/// Maps nixpkgs '.version' (like '24.11') and optional '.version-suffix'
+/// (like 'pre712345.abcdef' or '.712345.abcdef') to repology repository.
+///
+/// Channel tarballs carry '.version-suffix' with a 'pre' marker for
+/// unstable channels. Flake inputs lack the suffix. There the version
+/// is compared to current date: 'master' is always ahead of the latest
+/// release.
+fn repo_for_version(version: &str, suffix: Option<&str>, now: (u32, u32)) -> Option<String> {
+    let (yy, mm) = version.trim().split_once('.')?;
+    let (y, m): (u32, u32) = (yy.parse().ok()?, mm.parse().ok()?);
+
+    let released = match suffix.map(str::trim) {
+        Some(s) if !s.is_empty() => !s.starts_with("pre"),
+        _ => (2000 + y, m) <= now,
+    };
+    if released {
+        Some(format!("nix_stable_{yy}_{mm}"))
+    } else {
+        Some(UNSTABLE_REPO.to_string())
+    }
+}
+
+/// Detects repology repository matching the channel of 'nixpkgs' at
+/// 'path'. 'None' if the version can't be detected.
+fn detect_repology_repo(path: &str) -> Option<String> {
+    let version = match std::fs::read_to_string(format!("{path}/.version"))
+        .or_else(|_| std::fs::read_to_string(format!("{path}/lib/.version")))
+    {
+        Ok(v) => v,
+        Err(e) => {
+            log::info!("Failed to read nixpkgs version in {path}: {e}");
+            return None;
+        }
+    };
+    let suffix = std::fs::read_to_string(format!("{path}/.version-suffix")).ok();
+    let repo = repo_for_version(&version, suffix.as_deref(), current_year_month());
+    log::debug!("nixpkgs version {version:?} (suffix {suffix:?}) maps to {repo:?}");
+    repo
+}
+
+/// Detects repology repository matching the channel of system's
+/// 'nixpkgs'. 'None' if the version can't be detected.
+pub(crate) fn system_repology_repo(
+    nixpkgs: &Option<String>,
+    nixos_flake: &Flake,
+    ctx: &CmdContext,
+) -> Result<Option<String>, OldeError> {
+    let path = match nixpkgs {
+        Some(p) => resolve_flake(p),
+        None => available::get_system_nixpkgs(nixos_flake, ctx)?,
+    };
+    let repo = detect_repology_repo(&path);
+    if repo.is_none() {
+        log::info!("Failed to detect nixpkgs channel in {path}.");
+    }
+    Ok(repo)
+}
+
+/// Returns repology repository to compare against. Explicit 'repo'
+/// always wins. Otherwise with 'autodetect' it is derived from the
+/// channel of system's 'nixpkgs'.
+pub(crate) fn repology_repo(
+    repo: &Option<String>,
+    autodetect: bool,
+    nixpkgs: &Option<String>,
+    nixos_flake: &Flake,
+    ctx: &CmdContext,
+) -> Result<String, OldeError> {
+    if let Some(r) = repo {
+        return Ok(r.clone());
+    }
+    if !autodetect {
+        return Ok(UNSTABLE_REPO.to_string());
+    }
+
+    let repo = system_repology_repo(nixpkgs, nixos_flake, ctx)?.unwrap_or_else(|| {
+        log::info!("Assuming unstable nixpkgs channel.");
+        UNSTABLE_REPO.to_string()
+    });
+    log::info!("Using '{repo}' repology repository");
+    Ok(repo)
+}
+
+/// Returns revision of 'nixpkgs' input of a system flake from its lock
+/// file, like '5e4fbfb6b3de1aa2872b76d49fafc942626e2add (2025-01-14)'.
+fn flake_nixpkgs_revision(
+    nixos_flake: &Flake,
+    ctx: &CmdContext,
+) -> Result<Option<String>, OldeError> {
+    let mut cmd: Vec<&str> = nix_cmd(ctx)?;
+    let path = nixos_flake.path();
+    cmd.extend_from_slice(&["flake", "metadata", "--json", &path]);
+    let out_u8 = run_cmd(&cmd, ctx)?;
+    // { "locks": { "nodes": {
+    //       "nixpkgs": { "locked": { "lastModified": 1736867362,
+    //                                "rev": "5e4fbfb6...", ... } },
+    //       "root": { "inputs": { "nixpkgs": "nixpkgs", ... } }, ...
+    //     }, "root": "root" }, ... }
+    #[derive(Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    struct Locked {
+        rev: Option<String>,
+        last_modified: Option<u64>,
+    }
+    #[derive(Deserialize, Debug)]
+    struct Node {
+        // Either a node name or a 'follows' path.
+        #[serde(default)]
+        inputs: BTreeMap<String, serde_json::Value>,
+        locked: Option<Locked>,
+    }
+    #[derive(Deserialize, Debug)]
+    struct Locks {
+        nodes: BTreeMap<String, Node>,
+        root: String,
+    }
+    #[derive(Deserialize, Debug)]
+    struct Metadata {
+        locks: Locks,
+    }
+
+    let m: Metadata = serde_json::from_slice(out_u8.as_slice())?;
+    let nodes = &m.locks.nodes;
+    let locked = nodes
+        .get(&m.locks.root)
+        .and_then(|r| r.inputs.get("nixpkgs"))
+        .and_then(|n| n.as_str())
+        .and_then(|n| nodes.get(n))
+        .and_then(|n| n.locked.as_ref());
+    Ok(locked.and_then(|l| {
+        let rev = l.rev.clone()?;
+        Some(match l.last_modified {
+            Some(lm) => {
+                let (y, m, d) = civil_date(lm);
+                format!("{rev} ({y:04}-{m:02}-{d:02})")
+            }
+            None => rev,
+        })
+    }))
+}
+
+/// Returns git revision of system's 'nixpkgs': '.git-revision' of
+/// channel tarballs or locked 'nixpkgs' input of a system flake.
+/// 'None' if the revision is unknown.
+pub(crate) fn system_nixpkgs_revision(
+    nixpkgs: &Option<String>,
+    nixos_flake: &Flake,
+    ctx: &CmdContext,
+) -> Result<Option<String>, OldeError> {
+    let path = match nixpkgs {
+        Some(p) => resolve_flake(p),
+        None => available::get_system_nixpkgs(nixos_flake, ctx)?,
+    };
+    if let Ok(rev) = std::fs::read_to_string(format!("{path}/.git-revision")) {
+        let rev = rev.trim();
+        if !rev.is_empty() {
+            return Ok(Some(rev.to_string()));
+        }
+    }
+    if nixpkgs.is_some() {
+        return Ok(None);
+    }
+    flake_nixpkgs_revision(nixos_flake, ctx)
+}
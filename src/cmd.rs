@@ -1,33 +1,341 @@
-use std::process::Command;
+use std::io::{BufReader, Read};
+use std::process::{ChildStdout, Command, ExitStatus, Output, Stdio};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 
 use crate::error::*;
 
-/// Runs 'cmd' and returns stdout or failure.
-pub(crate) fn run_cmd(args: &[&str]) -> Result<Vec<u8>, OldeError> {
-    let output = Command::new(args[0]).args(&args[1..]).output()?;
+fn never_canceled() -> bool {
+    false
+}
 
-    if !output.status.success() {
-        // Be verbose about all command run failures.
-        log::info!("Failed running {:?}: {:?}", args, output.status);
-        for l in output.stdout.split(|c| *c == b'\n').filter(|e| !e.is_empty()) {
-            log::info!("out> {}", String::from_utf8_lossy(l));
+/// Settings of external commands of a single 'run()'.
+#[derive(Clone, Copy)]
+pub(crate) struct CmdContext<'a> {
+    /// Set by '--dry-run': commands are printed instead of run.
+    pub(crate) dry_run: bool,
+    /// Maximum run time of a command. Unlimited if unset.
+    pub(crate) timeout: Option<Duration>,
+    /// Running commands are killed once it returns true.
+    cancel: &'a (dyn Fn() -> bool + Sync),
+}
+
+impl CmdContext<'static> {
+    /// Context of commands that are never canceled.
+    pub(crate) fn new(dry_run: bool, timeout: Option<Duration>) -> Self {
+        CmdContext {
+            dry_run,
+            timeout,
+            cancel: &never_canceled,
         }
-        for l in output.stderr.split(|c| *c == b'\n').filter(|e| !e.is_empty()) {
-            log::info!("err> {}", String::from_utf8_lossy(l));
+    }
+}
+
+impl CmdContext<'_> {
+    /// Returns the same settings with commands killed once 'cancel'
+    /// returns true.
+    pub(crate) fn with_cancel<'b>(&self, cancel: &'b (dyn Fn() -> bool + Sync)) -> CmdContext<'b> {
+        CmdContext {
+            dry_run: self.dry_run,
+            timeout: self.timeout,
+            cancel,
         }
-        return Err(OldeError::CommandFailed {
-            cmd: args.iter().map(|a| a.to_string()).collect(),
-            output,
+    }
+
+    pub(crate) fn is_canceled(&self) -> bool {
+        (self.cancel)()
+    }
+}
+
+/// Returns true if 'tool' is an existing file: a path as is or a bare
+/// name looked up in '$PATH'.
+fn tool_exists(tool: &str) -> bool {
+    if tool.contains('/') {
+        return std::path::Path::new(tool).is_file();
+    }
+    std::env::var_os("PATH")
+        .is_some_and(|p| std::env::split_paths(&p).any(|d| d.join(tool).is_file()))
+}
+
+/// Fails with 'MissingTool' on the first of 'tools' that does not
+/// exist. Saves a cryptic failure of a command in the middle of a run.
+pub(crate) fn check_tools(tools: &[&str]) -> Result<(), OldeError> {
+    match tools.iter().find(|t| !tool_exists(t)) {
+        Some(t) => Err(OldeError::MissingTool(t.to_string())),
+        None => Ok(()),
+    }
+}
+
+/// Quotes 'args' to be pasted into a POSIX shell.
+fn shell_quote(args: &[&str]) -> String {
+    args.iter()
+        .map(|a| {
+            let safe = !a.is_empty()
+                && a.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "_-./:=@%+,#".contains(c));
+            match safe {
+                true => a.to_string(),
+                false => format!("'{}'", a.replace('\'', "'\\''")),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// How often to check for running command completion.
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Runs command to completion passing its stdout to 'read_stdout' and
+/// collecting stderr. Kills it if it runs longer than 'ctx.timeout' or
+/// once 'ctx' is canceled.
+fn run_piped<T: Send>(
+    args: &[&str],
+    ctx: &CmdContext,
+    read_stdout: impl FnOnce(&mut ChildStdout) -> T + Send,
+) -> Result<(ExitStatus, T, Vec<u8>), OldeError> {
+    let mut child = Command::new(args[0])
+        .args(&args[1..])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let started = Instant::now();
+
+    // Pipes are drained in parallel to avoid a deadlock on large
+    // outputs.
+    let mut out_pipe = child.stdout.take().expect("piped stdout");
+    let mut err_pipe = child.stderr.take().expect("piped stderr");
+    std::thread::scope(|s| {
+        let out_reader = s.spawn(move || read_stdout(&mut out_pipe));
+        let err_reader = s.spawn(move || {
+            let mut buf = Vec::new();
+            err_pipe.read_to_end(&mut buf).map(|_| buf)
         });
+
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if ctx.is_canceled() {
+                log::info!("Killing {:?}: canceled", args);
+                child.kill()?;
+                child.wait()?;
+                return Err(OldeError::Canceled(shell_quote(args)));
+            }
+            if let Some(timeout) = ctx.timeout {
+                if started.elapsed() > timeout {
+                    log::info!("Killing {:?}: timed out after {:?}", args, timeout);
+                    child.kill()?;
+                    child.wait()?;
+                    return Err(OldeError::CommandTimeout {
+                        cmd: args.iter().map(|a| a.to_string()).collect(),
+                        secs: timeout.as_secs(),
+                    });
+                }
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        };
+
+        Ok((
+            status,
+            out_reader.join().expect("stdout reader"),
+            err_reader.join().expect("stderr reader")?,
+        ))
+    })
+}
+
+/// Runs command to completion collecting its output.
+fn run_output(args: &[&str], ctx: &CmdContext) -> Result<Output, OldeError> {
+    let (status, stdout, stderr) = run_piped(args, ctx, |p| {
+        let mut buf = Vec::new();
+        p.read_to_end(&mut buf).map(|_| buf)
+    })?;
+    Ok(Output {
+        status,
+        stdout: stdout?,
+        stderr,
+    })
+}
+
+/// Logs all output of failed 'args' command and wraps it into an
+/// error.
+fn command_failed(args: &[&str], output: Output) -> OldeError {
+    // Be verbose about all command run failures.
+    log::info!("Failed running {:?}: {:?}", args, output.status);
+    for l in output
+        .stdout
+        .split(|c| *c == b'\n')
+        .filter(|e| !e.is_empty())
+    {
+        log::info!("out> {}", String::from_utf8_lossy(l));
+    }
+    for l in output
+        .stderr
+        .split(|c| *c == b'\n')
+        .filter(|e| !e.is_empty())
+    {
+        log::info!("err> {}", String::from_utf8_lossy(l));
+    }
+    OldeError::CommandFailed {
+        cmd: args.iter().map(|a| a.to_string()).collect(),
+        output,
+    }
+}
+
+/// Runs 'cmd' and returns stdout or failure. In '--dry-run' mode only
+/// prints the command and fails with 'DryRun'. Canceled 'ctx' kills
+/// running command and fails with 'Canceled'.
+pub(crate) fn run_cmd(args: &[&str], ctx: &CmdContext) -> Result<Vec<u8>, OldeError> {
+    if ctx.dry_run {
+        let cmd = shell_quote(args);
+        eprintln!("$ {cmd}");
+        return Err(OldeError::DryRun(cmd));
+    }
+    run_cmd_always(args, ctx)
+}
+
+/// Runs 'cmd' even in '--dry-run' mode. Only meant for side-effect
+/// free queries like 'nix --version'.
+fn run_cmd_always(args: &[&str], ctx: &CmdContext) -> Result<Vec<u8>, OldeError> {
+    let output = run_output(args, ctx)?;
+
+    if !output.status.success() {
+        return Err(command_failed(args, output));
     } else {
         log::debug!("Running {:?}: {:?}", args, output.status);
-        for l in output.stdout.split(|c| *c == b'\n').filter(|e| !e.is_empty()) {
+        // Outputs are large (like repology pages): don't scan them
+        // unless they are printed.
+        if !log::log_enabled!(log::Level::Trace) {
+            return Ok(output.stdout);
+        }
+        for l in output
+            .stdout
+            .split(|c| *c == b'\n')
+            .filter(|e| !e.is_empty())
+        {
             log::trace!("out> {}", String::from_utf8_lossy(l));
         }
-        for l in output.stderr.split(|c| *c == b'\n').filter(|e| !e.is_empty()) {
+        for l in output
+            .stderr
+            .split(|c| *c == b'\n')
+            .filter(|e| !e.is_empty())
+        {
             log::trace!("err> {}", String::from_utf8_lossy(l));
         }
     }
 
     Ok(output.stdout)
 }
+
+/// Runs 'cmd' like 'run_cmd()' but feeds its stdout to 'parse' as it
+/// arrives instead of collecting it. Huge outputs (like 'nix-env -qa')
+/// are never held in memory along with their parsed form. 'parse'
+/// result is ignored if command fails.
+pub(crate) fn run_cmd_streaming<T: Send>(
+    args: &[&str],
+    ctx: &CmdContext,
+    parse: impl FnOnce(&mut dyn Read) -> Result<T, OldeError> + Send,
+) -> Result<T, OldeError> {
+    if ctx.dry_run {
+        let cmd = shell_quote(args);
+        eprintln!("$ {cmd}");
+        return Err(OldeError::DryRun(cmd));
+    }
+    let (status, r, stderr) = run_piped(args, ctx, |p| {
+        let r = parse(&mut BufReader::new(&mut *p));
+        // Unparsed leftovers would block the command on a full pipe.
+        let _ = std::io::copy(p, &mut std::io::sink());
+        r
+    })?;
+
+    if !status.success() {
+        let output = Output {
+            status,
+            stdout: Vec::new(),
+            stderr,
+        };
+        return Err(command_failed(args, output));
+    }
+    log::debug!("Running {:?}: {:?}", args, status);
+    if log::log_enabled!(log::Level::Trace) {
+        for l in stderr.split(|c| *c == b'\n').filter(|e| !e.is_empty()) {
+            log::trace!("err> {}", String::from_utf8_lossy(l));
+        }
+    }
+    r
+}
+
+/// Parsed 'nix --version' output.
+#[derive(Eq, PartialEq, Ord, PartialOrd, Debug, Clone, Copy)]
+pub(crate) struct NixVersion {
+    pub(crate) major: u32,
+    pub(crate) minor: u32,
+    pub(crate) patch: u32,
+}
+
+impl std::fmt::Display for NixVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// First version with 'nix-command' and 'flakes' experimental
+/// features and '--extra-experimental-features' option.
+const MIN_NIX_VERSION: NixVersion = NixVersion {
+    major: 2,
+    minor: 4,
+    patch: 0,
+};
+
+/// Parses version from outputs like:
+///     nix (Nix) 2.24.10
+///     nix (Lix, like Nix) 2.91.1
+///     nix (Nix) 2.26.0pre20241205_dirty
+fn parse_nix_version(s: &str) -> Option<NixVersion> {
+    let v = s.split_whitespace().last()?;
+    let mut cs = v.split('.').map(|c| {
+        let digits: String = c.chars().take_while(|c| c.is_ascii_digit()).collect();
+        digits.parse::<u32>().ok()
+    });
+    Some(NixVersion {
+        major: cs.next()??,
+        minor: cs.next()??,
+        patch: cs.next().flatten().unwrap_or(0),
+    })
+}
+
+/// Returns version of 'nix' in PATH. Runs 'nix --version' only once.
+pub(crate) fn nix_version(ctx: &CmdContext) -> Result<NixVersion, OldeError> {
+    static VERSION: OnceLock<NixVersion> = OnceLock::new();
+
+    if let Some(v) = VERSION.get() {
+        return Ok(*v);
+    }
+    let out = String::from_utf8(run_cmd_always(&["nix", "--version"], ctx)?)?;
+    let v = parse_nix_version(out.trim()).ok_or(OldeError::UnknownNixVersion(out.clone()))?;
+    log::debug!("Detected nix version {v}");
+    Ok(*VERSION.get_or_init(|| v))
+}
+
+/// Returns 'nix' command prefix with 'nix-command' and 'flakes'
+/// experimental features enabled. Fails on too old 'nix'.
+pub(crate) fn nix_cmd(ctx: &CmdContext) -> Result<Vec<&'static str>, OldeError> {
+    let v = match nix_version(ctx) {
+        // Commands are still worth printing without 'nix' around.
+        Err(_) if ctx.dry_run => MIN_NIX_VERSION,
+        r => r?,
+    };
+    if v < MIN_NIX_VERSION {
+        return Err(OldeError::NixTooOld {
+            version: v.to_string(),
+            required: MIN_NIX_VERSION.to_string(),
+        });
+    }
+    Ok(vec![
+        "nix",
+        "--extra-experimental-features",
+        "nix-command",
+        "--extra-experimental-features",
+        "flakes",
+    ])
+}
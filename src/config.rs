@@ -0,0 +1,196 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory};
+
+use nix_olde::*;
+
+use crate::opts::*;
+
+/// Returns default config file location:
+/// '$XDG_CONFIG_HOME/nix-olde/config.toml' or
+/// '~/.config/nix-olde/config.toml'.
+pub(crate) fn default_config_path() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(d) if !d.is_empty() => PathBuf::from(d),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("nix-olde").join("config.toml"))
+}
+
+/// Turns config file entries into command line arguments. Keys are long
+/// option names, like 'repology-repo = "nix_stable_24_11"'. Options
+/// passed on command line ('cli') are skipped: they take precedence.
+pub(crate) fn config_args(path: &Path, cli: &ArgMatches) -> Result<Vec<String>, OldeError> {
+    let invalid = |error: String| OldeError::InvalidConfig {
+        path: path.display().to_string(),
+        error,
+    };
+    let contents = std::fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+    parse_config(&contents, path, &|id| {
+        cli.value_source(id) == Some(ValueSource::CommandLine)
+    })
+    .map_err(invalid)
+}
+
+/// Turns a scalar config value into an option value.
+fn scalar(key: &str, v: &toml::Value) -> Result<String, String> {
+    match v {
+        toml::Value::String(s) => Ok(s.clone()),
+        toml::Value::Integer(i) => Ok(i.to_string()),
+        toml::Value::Float(f) => Ok(f.to_string()),
+        v => Err(format!("unsupported value of '{key}': {v}")),
+    }
+}
+
+/// Turns config file 'contents' into command line arguments. Arrays
+/// are turned into repeated options. Options taking a pair of values
+/// (like '--nix-arg') take a pair or an array of pairs:
+///     nix-arg = [["config", "{ allowUnfree = true; }"]]
+/// Keys of options with 'on_cli' ids are skipped. Unknown keys are
+/// reported and skipped.
+fn parse_config(
+    contents: &str,
+    path: &Path,
+    on_cli: &dyn Fn(&str) -> bool,
+) -> Result<Vec<String>, String> {
+    let table: toml::Table = contents.parse().map_err(|e| format!("{e}"))?;
+
+    let cmd = Opts::command();
+    let known: BTreeMap<&str, &clap::Arg> = cmd
+        .get_arguments()
+        .filter_map(|a| Some((a.get_long()?, a)))
+        // Nested configs are not supported.
+        .filter(|(l, _)| !["config", "no-config"].contains(l))
+        .collect();
+
+    let mut args = Vec::new();
+    for (key, value) in &table {
+        let arg = match known.get(key.as_str()) {
+            None => {
+                eprintln!("Warning: unknown key '{key}' in {path:?}, ignored.");
+                continue;
+            }
+            Some(a) => a,
+        };
+        if on_cli(arg.get_id().as_str()) {
+            log::debug!("Config key '{key}' is overridden by command line");
+            continue;
+        }
+        let pairs = arg.get_num_args().is_some_and(|n| n.min_values() == 2);
+        let values: Vec<&toml::Value> = match value {
+            // A single pair.
+            toml::Value::Array(vs) if pairs && !vs.iter().any(|v| v.is_array()) => vec![value],
+            toml::Value::Array(vs) => vs.iter().collect(),
+            v => vec![v],
+        };
+        for v in values {
+            match v {
+                toml::Value::Array(pair) if pairs && pair.len() == 2 => {
+                    args.push(format!("--{key}"));
+                    for p in pair {
+                        args.push(scalar(key, p)?);
+                    }
+                }
+                _ if pairs => return Err(format!("'{key}' needs a pair of values: {v}")),
+                toml::Value::Boolean(true) => args.push(format!("--{key}")),
+                toml::Value::Boolean(false) => {}
+                v => args.push(format!("--{key}={}", scalar(key, v)?)),
+            }
+        }
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(contents: &str, on_cli: &[&str]) -> Result<Vec<String>, String> {
+        parse_config(contents, Path::new("test.toml"), &|id| on_cli.contains(&id))
+    }
+
+    #[test]
+    fn expands_values() {
+        let r = args(
+            r#"
+                repology-repo = "nix_stable_24_11"
+                exclude = ["^python:", "^perl:"]
+                repology-retries = 5
+                summary = true
+                best-effort = false
+            "#,
+            &[],
+        );
+        assert_eq!(
+            r.unwrap(),
+            [
+                "--exclude=^python:",
+                "--exclude=^perl:",
+                "--repology-repo=nix_stable_24_11",
+                "--repology-retries=5",
+                "--summary",
+            ]
+        );
+    }
+
+    #[test]
+    fn command_line_takes_precedence() {
+        let r = args(
+            r#"
+                flake = "/tmp/cfgflake#one"
+                exclude = ["^python:"]
+                summary = true
+            "#,
+            &["flake", "exclude"],
+        );
+        assert_eq!(r.unwrap(), ["--summary"]);
+    }
+
+    #[test]
+    fn expands_pairs() {
+        let r = args(
+            r#"
+                nix-arg = [["config", "{ allowUnfree = true; }"], ["foo", "1"]]
+                nix-argstr = ["system", "aarch64-linux"]
+            "#,
+            &[],
+        );
+        assert_eq!(
+            r.unwrap(),
+            [
+                "--nix-arg",
+                "config",
+                "{ allowUnfree = true; }",
+                "--nix-arg",
+                "foo",
+                "1",
+                "--nix-argstr",
+                "system",
+                "aarch64-linux",
+            ]
+        );
+        assert!(args(r#"nix-arg = "config""#, &[]).is_err());
+        assert!(args(r#"nix-arg = [["a", "b", "c"]]"#, &[]).is_err());
+    }
+
+    #[test]
+    fn skips_unknown_and_nested_keys() {
+        let r = args(
+            r#"
+                no-such-option = 1
+                config = "/etc/other.toml"
+                summary = true
+            "#,
+            &[],
+        );
+        assert_eq!(r.unwrap(), ["--summary"]);
+    }
+
+    #[test]
+    fn rejects_unsupported_values() {
+        assert!(args("summary = 1979-05-27", &[]).is_err());
+        assert!(args("summary = [", &[]).is_err());
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use nix_olde::*;
+use serde_derive::Serialize;
+
+use crate::opts::*;
+use crate::output::*;
+
+/// Kind of a difference between two reports.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Change {
+    /// Package is present only in the new report.
+    New,
+    /// Package is present only in the old report.
+    Fixed,
+    /// Package versions or severity differ between reports.
+    Changed,
+}
+
+/// Difference of a single package between two reports.
+#[derive(Serialize, Debug)]
+pub(crate) struct DiffEntry {
+    pub(crate) change: Change,
+    pub(crate) repology_name: String,
+    /// Record of the old report. 'None' for 'New' packages.
+    pub(crate) old: Option<OutdatedPackage>,
+    /// Record of the new report. 'None' for 'Fixed' packages.
+    pub(crate) new: Option<OutdatedPackage>,
+}
+
+/// Reads a report saved with '--format ndjson' (compact or '--pretty')
+/// or '--format json-array' (with or without '--json-envelope').
+pub(crate) fn read_report(path: &str) -> Result<Vec<OutdatedPackage>, OldeError> {
+    let invalid = |error: String| OldeError::InvalidReport {
+        path: path.to_string(),
+        error,
+    };
+    let contents = std::fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+    if contents.trim_start().starts_with('[') {
+        return serde_json::from_str(&contents).map_err(|e| invalid(e.to_string()));
+    }
+    #[derive(serde_derive::Deserialize)]
+    struct Envelope {
+        outdated: Vec<OutdatedPackage>,
+    }
+    if let Ok(e) = serde_json::from_str::<Envelope>(&contents) {
+        return Ok(e.outdated);
+    }
+    serde_json::Deserializer::from_str(&contents)
+        .into_iter()
+        .collect::<Result<_, _>>()
+        .map_err(|e| invalid(e.to_string()))
+}
+
+/// Returns true if package gap differs between reports.
+fn gap_changed(old: &OutdatedPackage, new: &OutdatedPackage) -> bool {
+    old.nixpkgs_version != new.nixpkgs_version
+        || old.repology_version != new.repology_version
+        || old.severity != new.severity
+        || old.state != new.state
+}
+
+/// Compares packages of 'old' and 'new' reports by repology name.
+/// Unchanged packages and up to date records of '--show-all' reports
+/// are skipped.
+pub(crate) fn diff_reports(old: Vec<OutdatedPackage>, new: Vec<OutdatedPackage>) -> Vec<DiffEntry> {
+    let mut old: BTreeMap<String, OutdatedPackage> = old
+        .into_iter()
+        .filter(|p| p.is_outdated())
+        .map(|p| (p.repology_name.clone(), p))
+        .collect();
+    let mut r = Vec::new();
+    for p in new.into_iter().filter(|p| p.is_outdated()) {
+        let name = p.repology_name.clone();
+        match old.remove(&name) {
+            None => r.push(DiffEntry {
+                change: Change::New,
+                repology_name: name,
+                old: None,
+                new: Some(p),
+            }),
+            Some(o) if gap_changed(&o, &p) => r.push(DiffEntry {
+                change: Change::Changed,
+                repology_name: name,
+                old: Some(o),
+                new: Some(p),
+            }),
+            Some(_) => {}
+        }
+    }
+    r.extend(old.into_iter().map(|(name, o)| DiffEntry {
+        change: Change::Fixed,
+        repology_name: name,
+        old: Some(o),
+        new: None,
+    }));
+    r.sort_by(|a, b| a.repology_name.cmp(&b.repology_name));
+    r
+}
+
+/// Formats 'nixpkgs -> repology (severity)' gap of a package.
+fn gap(p: &OutdatedPackage) -> String {
+    format!(
+        "{} -> {} ({})",
+        p.nixpkgs_version
+            .iter()
+            .map(|v| v.as_str())
+            .collect::<Vec<_>>()
+            .join(", "),
+        p.repology_version.as_deref().unwrap_or("<none>"),
+        p.severity.as_str()
+    )
+}
+
+/// Writes differences as '+' (new), '-' (fixed) and '~' (changed)
+/// lines. 'JSON' formats emit 'DiffEntry' records, other formats fall
+/// back to plain lines.
+pub(crate) fn write_diff(
+    w: &mut dyn Write,
+    format: OutputFormat,
+    es: &[DiffEntry],
+    pretty: bool,
+) -> Result<(), OldeError> {
+    match format {
+        OutputFormat::Ndjson => write_ndjson(w, es, pretty)?,
+        OutputFormat::JsonArray => {
+            serde_json::to_writer_pretty(&mut *w, es)?;
+            writeln!(w)?;
+        }
+        _ => {
+            for e in es {
+                match (&e.old, &e.new) {
+                    (None, Some(n)) => writeln!(w, "+ {} {}", e.repology_name, gap(n))?,
+                    (Some(o), None) => writeln!(w, "- {} {}", e.repology_name, gap(o))?,
+                    (Some(o), Some(n)) => {
+                        writeln!(w, "~ {} {}, was {}", e.repology_name, gap(n), gap(o))?
+                    }
+                    (None, None) => {}
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,27 +1,125 @@
 use thiserror::Error;
 
+/// Number of trailing stderr lines to show for failed commands.
+const STDERR_TAIL_LINES: usize = 10;
+
+/// Returns last few non-empty stderr lines for a failure report.
+fn stderr_tail(stderr: &[u8]) -> String {
+    let s = String::from_utf8_lossy(stderr);
+    let lines: Vec<&str> = s.lines().filter(|l| !l.trim().is_empty()).collect();
+    if lines.is_empty() {
+        return String::from("<empty stderr>");
+    }
+    let tail = &lines[lines.len().saturating_sub(STDERR_TAIL_LINES)..];
+    tail.iter()
+        .map(|l| format!("  err> {l}"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Formats each error on its own line(s). Nested errors are indented.
+fn join_errors(errs: &[OldeError]) -> String {
+    errs.iter()
+        .map(|e| format!("- {e}").replace('\n', "\n  "))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Formats each error prefixed with a name of the method that failed.
+fn join_labeled_errors(errs: &[(&str, OldeError)]) -> String {
+    errs.iter()
+        .map(|(m, e)| format!("- {m}: {e}").replace('\n', "\n  "))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
 #[derive(Error, Debug)]
-pub(crate) enum OldeError {
-    /// Running external command failed for some reason.
-    #[error("command {cmd:?} failed: {output:?}")]
+pub enum OldeError {
+    /// Running external command failed for some reason. Full output
+    /// is logged with '--verbose'.
+    #[error(
+        "command {cmd:?} failed ({}):\n{}",
+        .output.status,
+        stderr_tail(&.output.stderr)
+    )]
     CommandFailed {
         cmd: Vec<String>,
         output: std::process::Output,
     },
 
+    /// External command did not finish in time and was killed.
+    #[error("command {cmd:?} timed out after {secs} s")]
+    CommandTimeout { cmd: Vec<String>, secs: u64 },
+
     // Multiple errors happened. See individual entries for an
     // explanation.
-    #[error("multiple errors: {0:?}")]
+    #[error("multiple errors:\n{}", join_errors(.0))]
     MultipleErrors(Vec<OldeError>),
 
+    // All alternative methods failed. Each error is labeled with its
+    // method name.
+    #[error("all methods failed:\n{}", join_labeled_errors(.0))]
+    AllMethodsFailed(Vec<(&'static str, OldeError)>),
+
     // Cancelled externally.
     #[error("canceled {0}")]
     Canceled(String),
 
+    // Command was not run due to '--dry-run'.
+    #[error("dry run: skipped {0}")]
+    DryRun(String),
+
+    // Repology pages do not advance or never end.
+    #[error("repology pagination failed at {suffix:?}: {reason}")]
+    RepologyPagination { suffix: String, reason: String },
+
+    // Required external binary is not installed.
+    #[error("required tool {0:?} is not found: install it or add it to PATH")]
+    MissingTool(String),
+
     // Unexpected empty output.
     #[error("unexpected empty output from {0}")]
     EmptyOutput(String),
 
+    // Unparseable 'nix --version' output.
+    #[error("unknown nix version: {0:?}")]
+    UnknownNixVersion(String),
+
+    // 'nix' does not support features we need.
+    #[error("nix {version} is too old, at least {required} is required")]
+    NixTooOld { version: String, required: String },
+
+    // Local hostname is not a valid UTF-8 string.
+    #[error("failed to decode hostname {0}, pass system name explicitly as '--host' or '--flake /etc/nixos#<name>'")]
+    HostnameError(String),
+
+    // '$USER' is not set or is not a valid UTF-8 string.
+    #[error("failed to get user name from $USER ({0}), pass home manager configuration explicitly as '--home-manager <flake>#<name>'")]
+    UserNameError(String),
+
+    // Flake has no configuration for requested system name.
+    #[error("no '{name}' in '{attribute}', did you mean one of: {}", .available.join(", "))]
+    UnknownConfiguration {
+        name: String,
+        attribute: String,
+        available: Vec<String>,
+    },
+
+    // Unreadable or malformed config file.
+    #[error("invalid config {path}: {error}")]
+    InvalidConfig { path: String, error: String },
+
+    // Unreadable or malformed saved report.
+    #[error("invalid report {path}: {error}")]
+    InvalidReport { path: String, error: String },
+
+    // Malformed user-supplied regular expression.
+    #[error("invalid regex {pattern:?}: {error}")]
+    InvalidRegex {
+        pattern: String,
+        error: regex::Error,
+    },
+
     // IO error: symlink resolution, file read failures.
     #[error("IO error: {0}")]
     IOError(std::io::Error),
@@ -31,6 +129,96 @@ pub(crate) enum OldeError {
 
     #[error("UTF8 decoding error: {0}")]
     UTF8Error(std::string::FromUtf8Error),
+
+    // HTTP request failure of in-process client.
+    #[cfg(feature = "http-reqwest")]
+    #[error("HTTP error: {0}")]
+    HTTPError(reqwest::Error),
+
+    // Background thread of in-process client died without a reply.
+    #[cfg(feature = "http-reqwest")]
+    #[error("HTTP fetch of {0} died without a response")]
+    HTTPFetchDied(String),
+}
+
+impl OldeError {
+    /// Returns machine-readable error description: 'kind' (variant
+    /// name), human-readable 'message' and variant-specific fields.
+    pub fn to_json(&self) -> serde_json::Value {
+        use serde_json::json;
+        let (kind, fields) = match self {
+            OldeError::CommandFailed { cmd, output } => (
+                "CommandFailed",
+                json!({
+                    "cmd": cmd,
+                    "status": output.status.code(),
+                    "stderr": String::from_utf8_lossy(&output.stderr),
+                }),
+            ),
+            OldeError::CommandTimeout { cmd, secs } => {
+                ("CommandTimeout", json!({"cmd": cmd, "secs": secs}))
+            }
+            OldeError::MultipleErrors(errs) => (
+                "MultipleErrors",
+                json!({"errors": errs.iter().map(|e| e.to_json()).collect::<Vec<_>>()}),
+            ),
+            OldeError::AllMethodsFailed(errs) => (
+                "AllMethodsFailed",
+                json!({"errors": errs
+                    .iter()
+                    .map(|(m, e)| json!({"method": m, "error": e.to_json()}))
+                    .collect::<Vec<_>>()}),
+            ),
+            OldeError::Canceled(what) => ("Canceled", json!({"source": what})),
+            OldeError::DryRun(what) => ("DryRun", json!({"source": what})),
+            OldeError::RepologyPagination { suffix, reason } => (
+                "RepologyPagination",
+                json!({"suffix": suffix, "reason": reason}),
+            ),
+            OldeError::MissingTool(tool) => ("MissingTool", json!({"tool": tool})),
+            OldeError::EmptyOutput(what) => ("EmptyOutput", json!({"source": what})),
+            OldeError::UnknownNixVersion(v) => ("UnknownNixVersion", json!({"output": v})),
+            OldeError::NixTooOld { version, required } => (
+                "NixTooOld",
+                json!({"version": version, "required": required}),
+            ),
+            OldeError::HostnameError(h) => ("HostnameError", json!({"hostname": h})),
+            OldeError::UserNameError(e) => ("UserNameError", json!({"error": e})),
+            OldeError::UnknownConfiguration {
+                name,
+                attribute,
+                available,
+            } => (
+                "UnknownConfiguration",
+                json!({"name": name, "attribute": attribute, "available": available}),
+            ),
+            OldeError::InvalidConfig { path, error } => {
+                ("InvalidConfig", json!({"path": path, "error": error}))
+            }
+            OldeError::InvalidReport { path, error } => {
+                ("InvalidReport", json!({"path": path, "error": error}))
+            }
+            OldeError::InvalidRegex { pattern, error } => (
+                "InvalidRegex",
+                json!({"pattern": pattern, "error": error.to_string()}),
+            ),
+            OldeError::IOError(_) => ("IOError", json!({})),
+            OldeError::JSONError(_) => ("JSONError", json!({})),
+            OldeError::UTF8Error(_) => ("UTF8Error", json!({})),
+            #[cfg(feature = "http-reqwest")]
+            OldeError::HTTPError(e) => (
+                "HTTPError",
+                json!({"status": e.status().map(|s| s.as_u16())}),
+            ),
+            #[cfg(feature = "http-reqwest")]
+            OldeError::HTTPFetchDied(url) => ("HTTPFetchDied", json!({"url": url})),
+        };
+        let mut r = json!({"kind": kind, "message": self.to_string()});
+        if let (Some(r), serde_json::Value::Object(fs)) = (r.as_object_mut(), fields) {
+            r.extend(fs);
+        }
+        r
+    }
 }
 
 impl From<std::io::Error> for OldeError {
@@ -50,3 +238,10 @@ impl From<std::string::FromUtf8Error> for OldeError {
         OldeError::UTF8Error(error)
     }
 }
+
+#[cfg(feature = "http-reqwest")]
+impl From<reqwest::Error> for OldeError {
+    fn from(error: reqwest::Error) -> Self {
+        OldeError::HTTPError(error)
+    }
+}
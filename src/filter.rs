@@ -0,0 +1,85 @@
+use std::collections::BTreeSet;
+
+use regex::Regex;
+
+use crate::error::*;
+
+/// Reads a list of repology names to ignore. One name per line,
+/// '#' starts a comment, blank lines are skipped.
+pub(crate) fn read_ignore_file(path: &str) -> Result<BTreeSet<String>, OldeError> {
+    let contents = std::fs::read_to_string(path)?;
+
+    Ok(contents
+        .lines()
+        .map(|l| match l.split_once('#') {
+            None => l,
+            Some((n, _comment)) => n,
+        })
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty())
+        .map(|l| l.to_string())
+        .collect())
+}
+
+/// Returns true if 'attribute' starts with any of 'prefixes'. Empty
+/// 'prefixes' list matches everything.
+pub(crate) fn matches_attr_prefix(attribute: &str, prefixes: &[String]) -> bool {
+    prefixes.is_empty() || prefixes.iter().any(|p| attribute.starts_with(p.as_str()))
+}
+
+/// Compiles '--exclude' patterns.
+pub(crate) fn compile_excludes(patterns: &[String]) -> Result<Vec<Regex>, OldeError> {
+    patterns
+        .iter()
+        .map(|p| {
+            Regex::new(p).map_err(|error| OldeError::InvalidRegex {
+                pattern: p.clone(),
+                error,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ignore_file_skips_comments_and_blank_lines() {
+        let path = std::env::temp_dir().join(format!("nix-olde-ignore-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "# stale\nzlib\n\n  python:networkx  # pinned\n#hello\n",
+        )
+        .unwrap();
+        let names = read_ignore_file(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            names,
+            BTreeSet::from(["python:networkx".to_string(), "zlib".to_string()])
+        );
+    }
+
+    #[test]
+    fn attr_prefix_matches_any_prefix() {
+        let prefixes = ["python3Packages.".to_string(), "nixos.zlib".to_string()];
+        assert!(matches_attr_prefix("python3Packages.networkx", &prefixes));
+        assert!(matches_attr_prefix("nixos.zlib", &prefixes));
+        assert!(!matches_attr_prefix("nixos.hello", &prefixes));
+        assert!(matches_attr_prefix("nixos.hello", &[]));
+    }
+
+    #[test]
+    fn excludes_are_unanchored_regexes() {
+        let res = compile_excludes(&["^python:".to_string(), "gcc".to_string()]).unwrap();
+        let excluded = |n: &str| res.iter().any(|re| re.is_match(n));
+        assert!(excluded("python:networkx"));
+        assert!(excluded("gcc-unwrapped"));
+        assert!(!excluded("zlib"));
+
+        match compile_excludes(&["zlib".to_string(), "(".to_string()]) {
+            Err(OldeError::InvalidRegex { pattern, .. }) => assert_eq!(pattern, "("),
+            r => panic!("unexpected result: {r:?}"),
+        }
+    }
+}
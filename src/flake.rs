@@ -1,3 +1,8 @@
+use serde_derive::Deserialize;
+
+use crate::cmd::*;
+use crate::error::*;
+
 /// Flake attribute used to construct system
 pub(crate) struct Flake {
     /// Path to a flake (without an attribute). Examples are:
@@ -8,6 +13,15 @@ pub(crate) struct Flake {
     configurations_attribute: String,
     /// System name as an attribute in `nixosConfigurations`.
     name: String,
+    /// Set when 'name' was requested by user (and not derived from
+    /// local hostname).
+    explicit_name: bool,
+    /// Attribute of a configuration to take derivation from:
+    /// `config.system.build.toplevel` or `activationPackage`.
+    toplevel_attribute: String,
+    /// Platform to evaluate for, like 'aarch64-linux'. Local one if
+    /// unset.
+    system: Option<String>,
 }
 
 /// Ideally we would just use flake path as is. In practice we have to
@@ -26,35 +40,93 @@ pub(crate) fn resolve_flake(s: &str) -> String {
     }
 }
 
-impl Flake {
-    pub(crate) fn new(s: &Option<String>) -> Flake {
-        // Disambiguate 2 forms:
-        // 1. with explicit attribute: /etc/nixos#vm
-        // 2. without the attribute: /etc/nixos (needs hostname access)
+/// Returns true if '--nixpkgs' argument is a flake reference like
+/// 'github:NixOS/nixpkgs/nixos-24.11' and not a local path.
+pub(crate) fn is_flake_reference(s: &str) -> bool {
+    s.contains(':') && !std::path::Path::new(s).exists()
+}
 
-        // TODO: propagate the error up.
-        let hostname = gethostname::gethostname()
-            .into_string()
-            .expect("hostname decoding failure");
+/// Resolves '--nixpkgs' argument to a local path. Flake references
+/// are fetched into the store.
+pub(crate) fn resolve_nixpkgs(s: &str, ctx: &CmdContext) -> Result<String, OldeError> {
+    if !is_flake_reference(s) {
+        return Ok(s.to_string());
+    }
 
+    let mut cmd: Vec<&str> = nix_cmd(ctx)?;
+    cmd.extend_from_slice(&["flake", "metadata", "--json", s]);
+    let out_u8 = run_cmd(&cmd, ctx)?;
+    // { "path": "/nix/store/...-source", "locked": { ... }, ... }
+    #[derive(Deserialize, Debug)]
+    struct Metadata {
+        path: String,
+    }
+    let m: Metadata = serde_json::from_slice(out_u8.as_slice())?;
+    log::debug!("Resolved nixpkgs {s} to {}", m.path);
+    Ok(m.path)
+}
+
+impl Flake {
+    pub(crate) fn new(
+        s: &Option<String>,
+        host: &Option<String>,
+        system: &Option<String>,
+    ) -> Result<Flake, OldeError> {
+        // Disambiguate 3 forms:
+        // 1. with explicit attribute: /etc/nixos#vm
+        // 2. without the attribute, with '--host': /etc/nixos
+        // 3. without the attribute: /etc/nixos (needs hostname access)
         let flake_uri = s.as_deref().unwrap_or("/etc/nixos");
-        let (flake, name): (&str, &str) = match flake_uri.split_once('#') {
-            None => (flake_uri, &hostname),
-            Some(fln) => fln,
+        let (flake, name, explicit_name): (&str, String, bool) = match flake_uri.split_once('#') {
+            Some((f, n)) => (f, n.to_string(), true),
+            None => match host {
+                Some(h) => (flake_uri, h.clone(), true),
+                None => {
+                    let hostname = gethostname::gethostname()
+                        .into_string()
+                        .map_err(|h| OldeError::HostnameError(format!("{h:?}")))?;
+                    (flake_uri, hostname, false)
+                }
+            },
         };
 
         let configurations_attribute = "nixosConfigurations";
         #[cfg(target_os = "macos")]
         let configurations_attribute = "darwinConfigurations";
 
-        Flake {
+        Ok(Flake {
             // TODO: try to resolve symlinks for paths in flake syntax
             // like 'git+file:///etc/nixos' (if `nixos-rebuild` supports
             // it).
             flake: resolve_flake(flake),
-            name: name.to_string(),
-            configurations_attribute: configurations_attribute.to_string()
-        }
+            name,
+            explicit_name,
+            configurations_attribute: configurations_attribute.to_string(),
+            toplevel_attribute: "config.system.build.toplevel".to_string(),
+            system: system.clone(),
+        })
+    }
+
+    /// Home manager flake. Accepts 2 forms:
+    /// 1. with explicit attribute: ~/hm#user@host
+    /// 2. without the attribute: ~/hm (uses $USER)
+    pub(crate) fn new_home_manager(s: &str, system: &Option<String>) -> Result<Flake, OldeError> {
+        let (flake, name): (&str, String) = match s.split_once('#') {
+            None => match std::env::var("USER") {
+                Ok(u) => (s, u),
+                Err(e) => return Err(OldeError::UserNameError(e.to_string())),
+            },
+            Some((f, n)) => (f, n.to_string()),
+        };
+
+        Ok(Flake {
+            flake: resolve_flake(flake),
+            name,
+            explicit_name: true,
+            configurations_attribute: "homeConfigurations".to_string(),
+            toplevel_attribute: "activationPackage".to_string(),
+            system: system.clone(),
+        })
     }
 
     /// The path part of original flake.
@@ -64,13 +136,45 @@ impl Flake {
         self.flake.to_string()
     }
 
+    /// System name within the flake. Example: for flake /etc/nixos#vm
+    /// it is a 'vm'.
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Attribute with all system configurations within the flake, like
+    /// 'nixosConfigurations'.
+    pub(crate) fn configurations_attribute(&self) -> &str {
+        &self.configurations_attribute
+    }
+
+    /// Platform to evaluate for, like 'aarch64-linux'. 'None' stands
+    /// for local platform.
+    pub(crate) fn system(&self) -> Option<&str> {
+        self.system.as_deref()
+    }
+
+    /// Returns true if system name was requested by user explicitly.
+    pub(crate) fn has_explicit_name(&self) -> bool {
+        self.explicit_name
+    }
+
     /// The attribute of requested system within the flake.
     /// TODO: not implemented yet. Just returns current system.
     pub(crate) fn system_attribute(&self) -> String {
+        // Names like 'user@host' need quoting.
+        let name = if self
+            .name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            self.name.clone()
+        } else {
+            format!("\"{}\"", self.name)
+        };
         format!(
-            "{}.{}.config.system.build.toplevel.drvPath",
-            self.configurations_attribute,
-            self.name
+            "{}.{}.{}.drvPath",
+            self.configurations_attribute, name, self.toplevel_attribute
         )
     }
 }
@@ -0,0 +1,194 @@
+use crate::cmd::*;
+use crate::error::*;
+
+/// Parameters of HTTP fetches.
+pub(crate) struct HttpOptions {
+    /// Explicit HTTP proxy URL. Environment proxies are used if unset.
+    pub(crate) proxy: Option<String>,
+    /// 'curl' binary to run.
+    pub(crate) curl_path: String,
+    /// Extra 'curl' arguments like '--cacert <file>'.
+    pub(crate) curl_args: Vec<String>,
+    /// In-process client shared by all fetches of a run. Built on
+    /// first use.
+    #[cfg(feature = "http-reqwest")]
+    client: std::sync::OnceLock<reqwest::blocking::Client>,
+}
+
+impl HttpOptions {
+    pub(crate) fn new(proxy: Option<String>, curl_path: String, curl_args: Vec<String>) -> Self {
+        HttpOptions {
+            proxy,
+            curl_path,
+            curl_args,
+            #[cfg(feature = "http-reqwest")]
+            client: std::sync::OnceLock::new(),
+        }
+    }
+}
+
+/// Prefix of HTTP status line 'curl' prints to stderr.
+const HTTP_CODE_PREFIX: &str = "nix-olde-http-code: ";
+
+/// Returns HTTP status of a failed 'curl' run out of its 'stderr'.
+/// 'None' if request was not made (like DNS failures) or 'curl' is too
+/// old to print it.
+fn curl_http_code(stderr: &[u8]) -> Option<u16> {
+    String::from_utf8_lossy(stderr)
+        .lines()
+        .rev()
+        .find_map(|l| l.strip_prefix(HTTP_CODE_PREFIX))
+        .and_then(|c| c.trim().parse().ok())
+        // 'curl' reports '000' if there was no response.
+        .filter(|c| *c != 0)
+}
+
+/// Returns true for HTTP statuses worth retrying: throttling (429) and
+/// server errors (5xx).
+fn is_transient_status(code: u16) -> bool {
+    code == 429 || (500..600).contains(&code)
+}
+
+/// Fetches 'url' contents over HTTP. Without explicit 'proxy' curl
+/// still honors '$http_proxy' and '$https_proxy'.
+#[cfg(not(feature = "http-reqwest"))]
+pub(crate) fn get(
+    url: &str,
+    user_agent: &str,
+    opts: &HttpOptions,
+    ctx: &CmdContext,
+) -> Result<Vec<u8>, OldeError> {
+    // '--fail' turns HTTP errors like 429 or 5xx into command failures.
+    // Their status is printed to stderr for 'is_transient()'.
+    let write_out = format!("%{{stderr}}{HTTP_CODE_PREFIX}%{{http_code}}\n");
+    let mut args = vec![
+        opts.curl_path.as_str(),
+        "--fail",
+        "--compressed",
+        "--write-out",
+        &write_out,
+        "--user-agent",
+        user_agent,
+    ];
+    if let Some(p) = &opts.proxy {
+        args.extend(["--proxy", p]);
+    }
+    args.extend(opts.curl_args.iter().map(|a| a.as_str()));
+    args.push(url);
+    run_cmd(&args, ctx)
+}
+
+/// Returns external binary needed for fetches: 'curl'.
+#[cfg(not(feature = "http-reqwest"))]
+pub(crate) fn required_tool(opts: &HttpOptions) -> Option<&str> {
+    Some(&opts.curl_path)
+}
+
+/// Returns external binary needed for fetches: none.
+#[cfg(feature = "http-reqwest")]
+pub(crate) fn required_tool(_opts: &HttpOptions) -> Option<&str> {
+    None
+}
+
+/// Fetches 'url' contents over HTTP. Without explicit 'proxy' the
+/// client honors '$http_proxy' and '$https_proxy'.
+#[cfg(feature = "http-reqwest")]
+pub(crate) fn get(
+    url: &str,
+    user_agent: &str,
+    opts: &HttpOptions,
+    ctx: &CmdContext,
+) -> Result<Vec<u8>, OldeError> {
+    static CURL_WARNING: std::sync::Once = std::sync::Once::new();
+    if opts.curl_path != "curl" || !opts.curl_args.is_empty() {
+        CURL_WARNING.call_once(|| {
+            log::warn!("'curl' options are ignored by in-process HTTP client");
+        });
+    }
+    if ctx.dry_run {
+        eprintln!("GET {url}");
+        return Err(OldeError::DryRun(url.to_string()));
+    }
+    let client = match opts.client.get() {
+        Some(c) => c,
+        None => {
+            let mut builder = reqwest::blocking::Client::builder()
+                .user_agent(user_agent)
+                .gzip(true);
+            // Command timeout bounds fetches just like 'curl' runs.
+            // Client's own default applies otherwise.
+            if let Some(t) = ctx.timeout {
+                builder = builder.timeout(t);
+            }
+            if let Some(p) = &opts.proxy {
+                builder = builder.proxy(reqwest::Proxy::all(p)?);
+            }
+            let built = builder.build()?;
+            opts.client.get_or_init(|| built)
+        }
+    };
+
+    // Blocking request can't be interrupted: it finishes in background
+    // once canceled.
+    use std::sync::mpsc::RecvTimeoutError;
+    let (tx, rx) = std::sync::mpsc::channel();
+    let (client, request_url) = (client.clone(), url.to_string());
+    std::thread::spawn(move || {
+        let r = client
+            .get(request_url)
+            .send()
+            .and_then(|r| r.error_for_status())
+            .and_then(|r| r.bytes());
+        let _ = tx.send(r);
+    });
+    loop {
+        match rx.recv_timeout(POLL_INTERVAL) {
+            Ok(r) => return Ok(r?.to_vec()),
+            Err(RecvTimeoutError::Timeout) if ctx.is_canceled() => {
+                log::info!("Abandoning GET {url}: canceled");
+                return Err(OldeError::Canceled(format!("GET {url}")));
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(OldeError::HTTPFetchDied(url.to_string()))
+            }
+        }
+    }
+}
+
+/// Returns true if failed request is worth retrying later: throttling
+/// or server errors. Failures without HTTP status (like DNS errors,
+/// timeouts or a missing 'curl') are not retried.
+pub(crate) fn is_transient(e: &OldeError) -> bool {
+    match e {
+        #[cfg(feature = "http-reqwest")]
+        OldeError::HTTPError(re) => re.status().is_some_and(|s| is_transient_status(s.as_u16())),
+        OldeError::CommandFailed { output, .. } => {
+            curl_http_code(&output.stderr).is_some_and(is_transient_status)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_curl_http_code() {
+        let stderr = b"  % Total    % Received\ncurl: (22) The requested URL returned error: 429\nnix-olde-http-code: 429\n";
+        assert_eq!(curl_http_code(stderr), Some(429));
+        assert_eq!(curl_http_code(b"nix-olde-http-code: 000\n"), None);
+        assert_eq!(curl_http_code(b"curl: (6) Could not resolve host\n"), None);
+    }
+
+    #[test]
+    fn retries_throttling_and_server_errors_only() {
+        assert!(is_transient_status(429));
+        assert!(is_transient_status(500));
+        assert!(is_transient_status(503));
+        assert!(!is_transient_status(404));
+        assert!(!is_transient_status(403));
+        assert!(!is_transient_status(200));
+    }
+}
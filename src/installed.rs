@@ -1,40 +1,68 @@
 use std::collections::BTreeMap;
 use std::collections::BTreeSet;
 
-use serde_derive::Deserialize;
+use serde_derive::{Deserialize, Serialize};
 
+use crate::cache::*;
 use crate::cmd::*;
 use crate::error::*;
 use crate::flake::*;
 
 /// Installed packages with available 'pname' and 'version' attributes.
-#[derive(Eq, PartialEq, Ord, PartialOrd, Debug)]
+#[derive(Eq, PartialEq, Ord, PartialOrd, Debug, Serialize, Deserialize)]
 pub(crate) struct Package {
     /// Full not-quite-'pname' + 'version' from package environment.
     pub(crate) name: String,
     /// 'version' attribute from package environment. Most trusted.
     pub(crate) version: String,
+    /// 'pname' attribute from package environment if present. Unlike
+    /// 'pname' of available packages it lacks prefixes like
+    /// 'python3.12-'.
+    pub(crate) pname: Option<String>,
+    /// Output store paths of all derivations with this 'name' and
+    /// 'version'. Not necessarily built.
+    #[serde(default)]
+    pub(crate) out_paths: BTreeSet<String>,
+}
+
+/// Merges packages differing only in 'out_paths': outputs of a single
+/// package or identical packages built a few times. Profile packages
+/// lack 'pname': it is taken from whichever entry has one.
+fn merge_outputs(ps: impl IntoIterator<Item = Package>) -> BTreeSet<Package> {
+    let mut r: BTreeMap<(String, String), (Option<String>, BTreeSet<String>)> = BTreeMap::new();
+    for p in ps {
+        let (pname, out_paths) = r.entry((p.name, p.version)).or_default();
+        if pname.is_none() {
+            *pname = p.pname;
+        }
+        out_paths.extend(p.out_paths);
+    }
+    r.into_iter()
+        .map(|((name, version), (pname, out_paths))| Package {
+            name,
+            version,
+            pname,
+            out_paths,
+        })
+        .collect()
 }
 
 fn get_local_system_derivation_via_flakes(
     nixpkgs: &Option<String>,
     nixos_flake: &Flake,
+    ctx: &CmdContext,
 ) -> Result<String, OldeError> {
     let flake_sys_attr = format!("{}#{}", nixos_flake.path(), nixos_flake.system_attribute());
 
-    let mut cmd: Vec<&str> = vec![
-        "nix",
-        "--extra-experimental-features",
-        "nix-command",
-        "--extra-experimental-features",
-        "flakes",
+    let mut cmd: Vec<&str> = nix_cmd(ctx)?;
+    cmd.extend_from_slice(&[
         "eval",
         // pessimistic case of impure flake
         // TODO: allow passing these flags explicitly when needed
         "--impure",
         "--raw",
         &flake_sys_attr,
-    ];
+    ]);
     let resolved_nixpkgs: String;
     match nixpkgs {
         None => {}
@@ -43,11 +71,88 @@ fn get_local_system_derivation_via_flakes(
             cmd.extend_from_slice(&["--override-input", "nixpkgs", &resolved_nixpkgs]);
         }
     }
-    let out_u8 = run_cmd(&cmd)?;
+    // Impure configurations pick platform from 'builtins.currentSystem'.
+    if let Some(s) = nixos_flake.system() {
+        cmd.extend_from_slice(&["--system", s]);
+    }
+    let out_u8 = run_cmd(&cmd, ctx)?;
     Ok(String::from_utf8(out_u8)?)
 }
 
-fn get_local_system_derivation_via_nixos(nixpkgs: &Option<String>) -> Result<String, OldeError> {
+/// Returns names of all configurations defined in the flake.
+fn get_flake_configurations(
+    nixos_flake: &Flake,
+    ctx: &CmdContext,
+) -> Result<Vec<String>, OldeError> {
+    let attr = format!(
+        "{}#{}",
+        nixos_flake.path(),
+        nixos_flake.configurations_attribute()
+    );
+    let mut cmd: Vec<&str> = nix_cmd(ctx)?;
+    cmd.extend_from_slice(&[
+        "eval",
+        "--impure",
+        "--json",
+        &attr,
+        "--apply",
+        "builtins.attrNames",
+    ]);
+    let out_u8 = run_cmd(&cmd, ctx)?;
+    Ok(serde_json::from_slice(out_u8.as_slice())?)
+}
+
+/// Turns flake evaluation failure into a list of valid configuration
+/// names if requested one does not exist.
+fn explain_flake_failure(nixos_flake: &Flake, err: OldeError, ctx: &CmdContext) -> OldeError {
+    if let OldeError::DryRun(_) | OldeError::Canceled(_) = err {
+        return err;
+    }
+    match get_flake_configurations(nixos_flake, ctx) {
+        Ok(available) if !available.iter().any(|n| n == nixos_flake.name()) => {
+            OldeError::UnknownConfiguration {
+                name: nixos_flake.name().to_string(),
+                attribute: nixos_flake.configurations_attribute().to_string(),
+                available,
+            }
+        }
+        // Not a flake or the configuration exists and fails for some
+        // other reason.
+        _ => err,
+    }
+}
+
+/// Fails early if explicitly requested configuration does not exist in
+/// the flake. Cheap compared to the evaluation of the configuration.
+/// Non-flake systems and other failures are left to the evaluation.
+pub(crate) fn check_flake_configuration(
+    nixos_flake: &Flake,
+    ctx: &CmdContext,
+) -> Result<(), OldeError> {
+    if !nixos_flake.has_explicit_name() {
+        return Ok(());
+    }
+    match get_flake_configurations(nixos_flake, ctx) {
+        Ok(available) if !available.iter().any(|n| n == nixos_flake.name()) => {
+            Err(OldeError::UnknownConfiguration {
+                name: nixos_flake.name().to_string(),
+                attribute: nixos_flake.configurations_attribute().to_string(),
+                available,
+            })
+        }
+        Ok(_) => Ok(()),
+        Err(e) => {
+            log::debug!("Failed to list flake configurations: {e}");
+            Ok(())
+        }
+    }
+}
+
+fn get_local_system_derivation_via_nixos(
+    nixpkgs: &Option<String>,
+    system: Option<&str>,
+    ctx: &CmdContext,
+) -> Result<String, OldeError> {
     // 'nix eval' could also do here, but it will force a copy. Which
     // takes a few seconds even on SSD. Might be worth it longer term?
     let mut cmd: Vec<&str> = vec!["nix-instantiate", "<nixpkgs/nixos>", "-A", "system"];
@@ -59,7 +164,10 @@ fn get_local_system_derivation_via_nixos(nixpkgs: &Option<String>) -> Result<Str
             cmd.extend_from_slice(&["-I", &a]);
         }
     }
-    let out_u8 = run_cmd(&cmd)?;
+    if let Some(s) = system {
+        cmd.extend_from_slice(&["--argstr", "system", s]);
+    }
+    let out_u8 = run_cmd(&cmd, ctx)?;
     // Returns path to derivation file (and a newline)
     let out_s = String::from_utf8(out_u8)?;
     // Have to drop trailing newline.
@@ -71,40 +179,132 @@ fn get_local_system_derivation_via_nixos(nixpkgs: &Option<String>) -> Result<Str
 fn get_local_system_derivation(
     nixpkgs: &Option<String>,
     nixos_flake: &Flake,
+    ctx: &CmdContext,
 ) -> Result<String, OldeError> {
     let mut errs = Vec::new();
 
     // Is there a helper for that?
-    let fr = get_local_system_derivation_via_flakes(nixpkgs, nixos_flake);
+    let fr = get_local_system_derivation_via_flakes(nixpkgs, nixos_flake, ctx);
     if fr.is_ok() {
         return fr;
     }
-    errs.push(fr.err().unwrap());
+    errs.push((
+        "flakes",
+        explain_flake_failure(nixos_flake, fr.err().unwrap(), ctx),
+    ));
+
+    // Non-flake evaluation always builds local configuration.
+    if nixos_flake.has_explicit_name() {
+        log::info!(
+            "Skipping non-flake evaluation: it can't select '{}' system.",
+            nixos_flake.name()
+        );
+        return Err(OldeError::AllMethodsFailed(errs));
+    }
 
-    let er = get_local_system_derivation_via_nixos(nixpkgs);
+    let er = get_local_system_derivation_via_nixos(nixpkgs, nixos_flake.system(), ctx);
     if er.is_ok() {
         return er;
     }
-    errs.push(er.err().unwrap());
+    errs.push(("nixos", er.err().unwrap()));
 
-    Err(OldeError::MultipleErrors(errs))
+    Err(OldeError::AllMethodsFailed(errs))
 }
 
-/// Returns list of all used derivations in parsed form.
-// TODO: add parameters like system expression.
-pub(crate) fn get_packages(
-    nixpkgs: &Option<String>,
-    nixos_flake: &Flake,
+/// Splits package name into 'pname' and 'version' the same way
+/// 'builtins.parseDrvName' does: version starts after the first dash
+/// not followed by a letter.
+pub(crate) fn parse_drv_name(name: &str) -> Option<(&str, &str)> {
+    let bytes = name.as_bytes();
+    let pos = (0..bytes.len()).find(|&i| {
+        bytes[i] == b'-' && bytes.get(i + 1).is_some_and(|c| !c.is_ascii_alphabetic())
+    })?;
+    Some((&name[..pos], &name[pos + 1..]))
+}
+
+/// Returns packages installed into 'nix profile'.
+fn get_profile_packages(profile: &str, ctx: &CmdContext) -> Result<BTreeSet<Package>, OldeError> {
+    let mut cmd: Vec<&str> = nix_cmd(ctx)?;
+    cmd.extend_from_slice(&["profile", "list", "--json", "--profile", profile]);
+    let out_u8 = run_cmd(&cmd, ctx)?;
+    // Older 'nix' versions return a list of elements, newer ones return
+    // a map keyed by element name:
+    // { "elements": { "hello": {
+    //       "attrPath": "legacyPackages.x86_64-linux.hello",
+    //       "storePaths": [ "/nix/store/...-hello-2.12.1" ],
+    //       ...
+    #[derive(Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    struct Element {
+        store_paths: Vec<String>,
+    }
+    #[derive(Deserialize, Debug)]
+    #[serde(untagged)]
+    enum Elements {
+        List(Vec<Element>),
+        Map(BTreeMap<String, Element>),
+    }
+    #[derive(Deserialize, Debug)]
+    struct Manifest {
+        elements: Elements,
+    }
+
+    let manifest: Manifest = serde_json::from_slice(out_u8.as_slice())?;
+    let elements: Vec<Element> = match manifest.elements {
+        Elements::List(es) => es,
+        Elements::Map(es) => es.into_values().collect(),
+    };
+
+    Ok(merge_outputs(
+        elements
+            .iter()
+            // The first store path is usually the main output.
+            .filter_map(|e| e.store_paths.first())
+            .filter_map(|sp| package_from_store_path(sp)),
+    ))
+}
+
+/// Non-default outputs have their name appended to store path name.
+const OUTPUT_SUFFIXES: &[&str] = &["-bin", "-dev", "-doc", "-info", "-lib", "-man", "-out"];
+
+/// Extracts package from store path like '/nix/store/<hash>-hello-2.12.1'.
+/// Versions are derived from store path names as nothing else is
+/// recorded. Unversioned paths are skipped just like unversioned
+/// derivations.
+fn package_from_store_path(sp: &str) -> Option<Package> {
+    // '/nix/store/<hash>-hello-2.12.1-man' -> 'hello-2.12.1'
+    let mut name = sp.rsplit('/').next()?.split_once('-')?.1;
+    if let Some(n) = OUTPUT_SUFFIXES.iter().find_map(|s| name.strip_suffix(s)) {
+        name = n;
+    }
+    match parse_drv_name(name) {
+        Some((_pname, version)) => Some(Package {
+            name: name.to_string(),
+            version: version.to_string(),
+            pname: None,
+            out_paths: BTreeSet::from([sp.to_string()]),
+        }),
+        None => {
+            log::debug!("Skipping unversioned store path {sp}");
+            None
+        }
+    }
+}
+
+/// Returns versioned derivations in closures of 'drv_paths'.
+fn query_derivations(
+    drv_paths: &[String],
+    ctx: &CmdContext,
 ) -> Result<BTreeSet<Package>, OldeError> {
-    let drv_path = get_local_system_derivation(nixpkgs, nixos_flake)?;
-    let drvs_u8 = run_cmd(&[
-        "nix",
-        "--extra-experimental-features",
-        "nix-command",
-        "show-derivation",
-        "-r",
-        &drv_path,
-    ])?;
+    let mut cmd: Vec<&str> = nix_cmd(ctx)?;
+    cmd.extend_from_slice(&["show-derivation", "-r"]);
+    cmd.extend(drv_paths.iter().map(|p| p.as_str()));
+    let drvs_u8 = run_cmd(&cmd, ctx)?;
+    parse_derivations(&drvs_u8)
+}
+
+/// Parses versioned derivations out of 'nix show-derivation' output.
+fn parse_derivations(drvs_u8: &[u8]) -> Result<BTreeSet<Package>, OldeError> {
     // {
     //   "/nix/store/...-python3.10-networkx-2.8.6.drv": {
     //     "env": {
@@ -116,32 +316,251 @@ pub(crate) fn get_packages(
     #[derive(Deserialize, Debug)]
     struct DrvEnv {
         name: Option<String>,
+        pname: Option<String>,
         version: Option<String>,
     }
     #[derive(Deserialize, Debug)]
+    struct DrvOutput {
+        // Absent for content-addressed derivations.
+        path: Option<String>,
+    }
+    #[derive(Deserialize, Debug)]
     /// Dervivation description with subset of fields needed to detect outdated packages.
     struct Installed {
         env: DrvEnv,
+        #[serde(default)]
+        outputs: BTreeMap<String, DrvOutput>,
     }
 
-    let drvs: BTreeMap<String, Installed> = serde_json::from_slice(drvs_u8.as_slice())?;
+    let drvs: BTreeMap<String, Installed> = serde_json::from_slice(drvs_u8)?;
 
-    let r: BTreeSet<_> = drvs
-        .iter()
-        .filter_map(|(_drv, oenv)| match &oenv.env {
+    Ok(merge_outputs(drvs.values().filter_map(|drv| {
+        match &drv.env {
             DrvEnv {
                 name: Some(n),
+                pname,
                 version: Some(ver),
             } => Some(Package {
                 name: n.clone(),
                 version: ver.clone(),
+                pname: pname.clone(),
+                out_paths: drv
+                    .outputs
+                    .values()
+                    .filter_map(|o| o.path.clone())
+                    .collect(),
             }),
             // Unversioned derivations. These are usually tarball
             // derivations and tiny wrapper shell scripts with one-off
             // commands.
             _ => None,
-        })
+        }
+    })))
+}
+
+/// Parses versioned packages out of 'nix path-info --json' output.
+fn parse_path_infos(infos_u8: &[u8]) -> Result<BTreeSet<Package>, OldeError> {
+    // Older 'nix' versions return a list of path infos, newer ones
+    // return a map keyed by store path:
+    // [ { "path": "/nix/store/...-hello-2.12.1", ... }, ... ]
+    // { "/nix/store/...-hello-2.12.1": { ... }, ... }
+    #[derive(Deserialize, Debug)]
+    struct PathInfo {
+        path: String,
+    }
+    #[derive(Deserialize, Debug)]
+    #[serde(untagged)]
+    enum PathInfos {
+        List(Vec<PathInfo>),
+        Map(BTreeMap<String, serde_json::Value>),
+    }
+    let paths: Vec<String> = match serde_json::from_slice(infos_u8)? {
+        PathInfos::List(ps) => ps.into_iter().map(|p| p.path).collect(),
+        PathInfos::Map(ps) => ps.into_keys().collect(),
+    };
+    Ok(merge_outputs(
+        paths
+            .iter()
+            .filter(|p| !p.ends_with(".drv"))
+            .filter_map(|p| package_from_store_path(p)),
+    ))
+}
+
+/// Returns closure sizes in bytes of 'out_paths' present in the store.
+/// Paths that were never built are skipped.
+pub(crate) fn get_closure_sizes(
+    out_paths: &BTreeSet<&str>,
+    ctx: &CmdContext,
+) -> Result<BTreeMap<String, u64>, OldeError> {
+    let present: Vec<&str> = out_paths
+        .iter()
+        .copied()
+        .filter(|p| std::path::Path::new(p).exists())
         .collect();
+    if present.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+    let mut cmd: Vec<&str> = nix_cmd(ctx)?;
+    cmd.extend_from_slice(&["path-info", "--closure-size", "--json"]);
+    cmd.extend(present);
+    let infos_u8 = run_cmd(&cmd, ctx)?;
+
+    // Older 'nix' versions return a list, newer ones return a map
+    // keyed by store path:
+    // [ { "path": "/nix/store/...-hello-2.12.1", "closureSize": 123, ... } ]
+    // { "/nix/store/...-hello-2.12.1": { "closureSize": 123, ... } }
+    #[derive(Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    struct SizeInfo {
+        #[serde(default)]
+        path: String,
+        closure_size: Option<u64>,
+    }
+    #[derive(Deserialize, Debug)]
+    #[serde(untagged)]
+    enum SizeInfos {
+        List(Vec<SizeInfo>),
+        Map(BTreeMap<String, Option<SizeInfo>>),
+    }
+    let infos: Vec<(String, Option<u64>)> = match serde_json::from_slice(&infos_u8)? {
+        SizeInfos::List(is) => is.into_iter().map(|i| (i.path, i.closure_size)).collect(),
+        SizeInfos::Map(is) => is
+            .into_iter()
+            .map(|(p, i)| (p, i.and_then(|i| i.closure_size)))
+            .collect(),
+    };
+    Ok(infos
+        .into_iter()
+        .filter_map(|(p, s)| Some((p, s?)))
+        .collect())
+}
+
+/// Returns packages of an existing system generation 'generation'
+/// without evaluating any configuration. Derivations of the toplevel
+/// are used if they are still present in the store. Otherwise versions
+/// are derived from runtime closure store path names.
+pub(crate) fn get_generation_packages(
+    generation: u32,
+    ctx: &CmdContext,
+) -> Result<BTreeSet<Package>, OldeError> {
+    let profiles = "/nix/var/nix/profiles";
+    let link = format!("{profiles}/system-{generation}-link");
+    let toplevel = match std::fs::canonicalize(&link) {
+        Ok(p) => p.to_string_lossy().to_string(),
+        Err(e) => {
+            log::debug!("Failed to resolve {link}: {e}");
+            let mut available: Vec<u32> = std::fs::read_dir(profiles)
+                .into_iter()
+                .flatten()
+                .filter_map(|e| e.ok())
+                .filter_map(|e| {
+                    let n = e.file_name().to_string_lossy().to_string();
+                    n.strip_prefix("system-")?
+                        .strip_suffix("-link")?
+                        .parse()
+                        .ok()
+                })
+                .collect();
+            available.sort();
+            return Err(OldeError::UnknownConfiguration {
+                name: generation.to_string(),
+                attribute: String::from(profiles),
+                available: available.iter().map(|g| g.to_string()).collect(),
+            });
+        }
+    };
+
+    let deriver_u8 = run_cmd(&["nix-store", "--query", "--deriver", &toplevel], ctx)?;
+    let deriver = String::from_utf8(deriver_u8)?.trim().to_string();
+    // Unknown deriver is reported as 'unknown-deriver'.
+    if deriver.ends_with(".drv") && std::path::Path::new(&deriver).exists() {
+        return query_derivations(&[deriver], ctx);
+    }
+    log::info!("Derivation of {toplevel} is not available, using its runtime closure");
+
+    let mut cmd: Vec<&str> = nix_cmd(ctx)?;
+    cmd.extend_from_slice(&["path-info", "-r", "--json", &toplevel]);
+    let infos_u8 = run_cmd(&cmd, ctx)?;
+    let r = parse_path_infos(&infos_u8)?;
+    if r.is_empty() {
+        return Err(OldeError::EmptyOutput(link));
+    }
+    Ok(r)
+}
+
+/// Returns packages of a closure captured elsewhere: a file with output
+/// of 'nix show-derivation -r' or 'nix path-info -r --json'.
+pub(crate) fn get_closure_packages(path: &str) -> Result<BTreeSet<Package>, OldeError> {
+    let contents = std::fs::read(path)?;
+    let r = match parse_derivations(&contents) {
+        Ok(r) => r,
+        Err(e) => {
+            log::debug!("{path} is not a 'nix show-derivation' output: {e}");
+            parse_path_infos(&contents)?
+        }
+    };
+
+    if r.is_empty() {
+        return Err(OldeError::EmptyOutput(path.to_string()));
+    }
+    Ok(r)
+}
+
+/// Returns list of all used derivations in parsed form. Closures of
+/// all 'nixos_flakes' are merged. With 'cache' parsed derivations are
+/// reused as long as system derivations stay the same.
+// TODO: add parameters like system expression.
+pub(crate) fn get_packages(
+    nixpkgs: &Option<String>,
+    nixos_flakes: &[Flake],
+    home_manager_flake: Option<&Flake>,
+    profile: Option<&str>,
+    cache: Option<&Cache>,
+    ctx: &CmdContext,
+) -> Result<BTreeSet<Package>, OldeError> {
+    let (nixos_flake, extra_flakes) = nixos_flakes
+        .split_first()
+        .expect("at least one system flake");
+    let mut drv_paths = vec![get_local_system_derivation(nixpkgs, nixos_flake, ctx)?];
+    // Extra system flakes (like containers) and home manager profile
+    // are merged into system's closure. Common derivations are
+    // deduplicated by 'show-derivation' itself. Only the first flake
+    // falls back to non-flake evaluation of local system.
+    for hm in extra_flakes.iter().chain(home_manager_flake) {
+        drv_paths.push(
+            get_local_system_derivation_via_flakes(nixpkgs, hm, ctx)
+                .map_err(|e| explain_flake_failure(hm, e, ctx))?,
+        );
+    }
+
+    // Any configuration change changes derivation paths. Thus cache
+    // entries never go stale. Hashed to fit file name length limit.
+    let drv_names = drv_paths
+        .iter()
+        .map(|p| p.rsplit('/').next().unwrap_or(p))
+        .collect::<Vec<_>>()
+        .join("+");
+    let cache_key = format!("installed-v2-{}", short_hash(&drv_names));
+    let mut r = match cache.and_then(|c| c.get_immutable_json(&cache_key)) {
+        Some(r) => {
+            log::info!("Using cached installed packages of {drv_paths:?}");
+            r
+        }
+        None => {
+            let r = query_derivations(&drv_paths, ctx)?;
+            if let Some(c) = cache {
+                if let Err(e) = c.put_json(&cache_key, &r) {
+                    log::warn!("Failed to cache installed packages: {e}");
+                }
+            }
+            r
+        }
+    };
+
+    if let Some(p) = profile {
+        // The same package can be both in system closure and in profile.
+        r = merge_outputs(r.into_iter().chain(get_profile_packages(p, ctx)?));
+    }
 
     // Misconfigured system, not a NixOS or flake-based system?
     if r.is_empty() {
@@ -150,3 +569,37 @@ pub(crate) fn get_packages(
 
     Ok(r)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_profile_packages_into_system_ones() {
+        let system = Package {
+            name: String::from("hello-2.12.1"),
+            version: String::from("2.12.1"),
+            pname: Some(String::from("hello")),
+            out_paths: BTreeSet::from([String::from("/nix/store/aaaa-hello-2.12.1")]),
+        };
+        let profile = ["/nix/store/bbbb-hello-2.12.1", "/nix/store/cccc-which-2.21"]
+            .into_iter()
+            .filter_map(package_from_store_path);
+
+        let r = merge_outputs(std::iter::once(system).chain(profile));
+
+        let r: Vec<_> = r.into_iter().collect();
+        assert_eq!(r.len(), 2);
+        assert_eq!(r[0].name, "hello-2.12.1");
+        assert_eq!(r[0].pname.as_deref(), Some("hello"));
+        assert_eq!(
+            r[0].out_paths,
+            BTreeSet::from([
+                String::from("/nix/store/aaaa-hello-2.12.1"),
+                String::from("/nix/store/bbbb-hello-2.12.1"),
+            ])
+        );
+        assert_eq!(r[1].name, "which-2.21");
+        assert_eq!(r[1].pname, None);
+    }
+}
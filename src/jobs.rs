@@ -0,0 +1,45 @@
+use std::num::NonZeroUsize;
+use std::sync::{Condvar, Mutex};
+
+/// Limits number of concurrently running heavy tasks.
+pub(crate) struct JobSlots {
+    /// Number of free slots. 'None' means no limit.
+    free: Mutex<Option<usize>>,
+    released: Condvar,
+}
+
+/// Occupied job slot. Released on drop.
+pub(crate) struct JobSlot<'a> {
+    slots: &'a JobSlots,
+}
+
+impl JobSlots {
+    pub(crate) fn new(limit: Option<NonZeroUsize>) -> Self {
+        JobSlots {
+            free: Mutex::new(limit.map(|l| l.get())),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Blocks until a slot is available.
+    pub(crate) fn acquire(&self) -> JobSlot<'_> {
+        let mut free = self.free.lock().expect("job slots lock");
+        while *free == Some(0) {
+            free = self.released.wait(free).expect("job slots lock");
+        }
+        if let Some(n) = free.as_mut() {
+            *n -= 1;
+        }
+        JobSlot { slots: self }
+    }
+}
+
+impl Drop for JobSlot<'_> {
+    fn drop(&mut self) {
+        let mut free = self.slots.free.lock().expect("job slots lock");
+        if let Some(n) = free.as_mut() {
+            *n += 1;
+        }
+        self.slots.released.notify_one();
+    }
+}
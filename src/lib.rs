@@ -0,0 +1,877 @@
+//! Finds installed packages of a NixOS system that are outdated
+//! according to repology.org. The 'nix-olde' binary is a thin wrapper
+//! around 'run()'.
+
+// TODO: can we move it out to Cargo.toml? Or a separate file?
+mod cache;
+mod channel;
+mod cmd;
+mod error;
+mod filter;
+mod flake;
+mod http;
+mod jobs;
+mod progress;
+mod report;
+mod version;
+
+// package loading modules
+mod available;
+mod installed;
+mod repology;
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::cache::*;
+use crate::cmd::*;
+use crate::filter::*;
+use crate::flake::*;
+use crate::jobs::*;
+use crate::progress::*;
+use crate::version::*;
+
+pub use crate::error::OldeError;
+pub use crate::report::*;
+pub use crate::version::Severity;
+
+/// Parameters of a 'run()'. Mirrors 'nix-olde' command line options.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct Config {
+    /// Alternative path to <nixpkgs> or a flake reference.
+    pub nixpkgs: Option<String>,
+    /// System flakes. Installed packages of all of them are merged.
+    /// The first one is used to detect nixpkgs channel. '/etc/nixos'
+    /// by default.
+    pub flake: Vec<String>,
+    /// System name within the flake. Local hostname by default.
+    pub host: Option<String>,
+    /// Home manager flake to add packages from.
+    pub home_manager: Option<String>,
+    /// 'nix profile' to add packages from.
+    pub profile: Option<String>,
+    /// Base URL of repology projects API with trailing slash.
+    pub repology_url: String,
+    /// Repology repository. 'nix_unstable' by default.
+    pub repology_repo: Option<String>,
+    /// Detect repology repository from nixpkgs version.
+    pub stable_channel: bool,
+    /// Number of retries of a failed repology page fetch.
+    pub repology_retries: u32,
+    /// Local dump of repology API responses to use instead of network.
+    pub repology_dump: Option<String>,
+    /// Minimum delay between repology page fetches.
+    pub repology_interval: Duration,
+    /// Number of repology name space ranges fetched in parallel.
+    pub repology_concurrency: NonZeroUsize,
+    /// Directory to cache fetched repology pages in.
+    pub cache_dir: Option<String>,
+    /// Maximum age of a cache entry to be reused.
+    pub cache_ttl: Duration,
+    /// Another nixpkgs to compare against instead of repology.
+    pub compare_nixpkgs: Option<String>,
+    /// Maximum number of concurrent 'nix' evaluations.
+    pub jobs: Option<NonZeroUsize>,
+    /// Maximum run time of external commands.
+    pub command_timeout: Option<Duration>,
+    /// Print external commands instead of running them.
+    pub dry_run: bool,
+    /// Hide progress output.
+    pub quiet: bool,
+    /// File with repology names to exclude from the report.
+    pub ignore_file: Option<String>,
+    /// Regular expressions of repology names to exclude from the report.
+    pub exclude: Vec<String>,
+    /// Only consider attributes starting with one of these prefixes.
+    pub attr_prefix: Vec<String>,
+    /// Minimum version gap to report.
+    pub min_severity: Severity,
+    /// Only consider repology packages with these statuses.
+    pub include_status: Vec<String>,
+    /// Also report packages newer than the latest known version.
+    pub show_ahead: bool,
+    /// Compare against pre-release latest versions like '2.0-rc1'
+    /// instead of the most recent stable ones.
+    pub include_pre_release: bool,
+    /// Only match packages to repology projects of the same major
+    /// version or a similar name.
+    pub strict_pname_match: bool,
+    /// Also report up to date packages as 'State::Current' records
+    /// with 'up_to_date' set. Needs 'all_repology_projects' to see
+    /// every package.
+    pub show_all: bool,
+    /// Add repology project web page to each outdated package.
+    pub repology_urls: bool,
+    /// Add closure size of installed store paths to each outdated
+    /// package.
+    pub with_size: bool,
+    /// Add repology maintainers to each outdated package. Takes an
+    /// extra repology fetch per package.
+    pub with_maintainers: bool,
+    /// 'config' expression of available packages query instead of
+    /// nixpkgs' 'packages-config.nix'.
+    pub packages_config: Option<String>,
+    /// Extra '--arg <name> <expression>' of available packages query.
+    pub nix_args: Vec<(String, String)>,
+    /// Extra '--argstr <name> <string>' of available packages query.
+    pub nix_argstrs: Vec<(String, String)>,
+    /// Only fetch repology packages and skip local evaluation.
+    pub repology_only: bool,
+    /// Only consider these repology projects. All if empty.
+    pub only: Vec<String>,
+    /// Proceed with partial results if some of data sources fail.
+    pub best_effort: bool,
+    /// Fetch all repology projects instead of outdated ones only.
+    /// Slower, but makes 'Report::missing_repology' accurate.
+    pub all_repology_projects: bool,
+    /// Platform to evaluate packages for, like 'aarch64-linux'. Local
+    /// one if unset.
+    pub system: Option<String>,
+    /// Skip 'nix-env -qa' query and match installed packages against
+    /// repology by their names. Attributes are unknown in this mode.
+    pub no_available: bool,
+    /// Read installed packages from a file with 'nix show-derivation -r'
+    /// or 'nix path-info -r --json' output instead of local system.
+    pub closure_json: Option<String>,
+    /// Read installed packages from an existing system generation
+    /// instead of evaluating system configuration.
+    pub generation: Option<u32>,
+    /// File to write installed packages to as JSON before matching.
+    pub dump_installed: Option<String>,
+    /// File to write available packages to as JSON before matching.
+    pub dump_available: Option<String>,
+    /// HTTP proxy for repology fetches.
+    pub proxy: Option<String>,
+    /// 'curl' binary used for repology fetches.
+    pub curl_path: String,
+    /// Extra 'curl' arguments for repology fetches.
+    pub curl_args: Vec<String>,
+    /// Caller identity appended to repology user agent.
+    pub user_agent_suffix: Option<String>,
+}
+
+/// Defaults of 'nix-olde' command line options. Unlike the binary it
+/// does not print progress: set 'quiet' to false to see it on stderr.
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            nixpkgs: None,
+            flake: Vec::new(),
+            host: None,
+            home_manager: None,
+            profile: None,
+            repology_url: String::from("https://repology.org/api/v1/projects/"),
+            repology_repo: None,
+            stable_channel: false,
+            repology_retries: 3,
+            repology_dump: None,
+            repology_interval: Duration::from_secs(1),
+            repology_concurrency: NonZeroUsize::MIN,
+            cache_dir: None,
+            cache_ttl: Duration::from_secs(3600),
+            compare_nixpkgs: None,
+            jobs: None,
+            command_timeout: None,
+            dry_run: false,
+            quiet: true,
+            ignore_file: None,
+            exclude: Vec::new(),
+            attr_prefix: Vec::new(),
+            min_severity: Severity::Patch,
+            include_status: Vec::new(),
+            show_ahead: false,
+            include_pre_release: false,
+            strict_pname_match: false,
+            show_all: false,
+            repology_urls: true,
+            with_size: false,
+            with_maintainers: false,
+            packages_config: None,
+            nix_args: Vec::new(),
+            nix_argstrs: Vec::new(),
+            repology_only: false,
+            only: Vec::new(),
+            best_effort: false,
+            all_repology_projects: false,
+            system: None,
+            no_available: false,
+            closure_json: None,
+            generation: None,
+            dump_installed: None,
+            dump_available: None,
+            proxy: None,
+            curl_path: String::from("curl"),
+            curl_args: Vec::new(),
+            user_agent_suffix: None,
+        }
+    }
+}
+
+/// Installed versions of a single repology project.
+#[derive(Default)]
+struct KnownVersion<'a> {
+    /// Latest version known to repology.
+    latest: Option<&'a str>,
+    /// Installed versions.
+    versions: BTreeSet<&'a str>,
+    /// nixpkgs attributes of installed packages.
+    attributes: BTreeSet<&'a str>,
+    /// Installed (attribute, version) pairs.
+    installed: BTreeSet<(&'a str, &'a str)>,
+    /// Repology statuses of nixpkgs packages.
+    statuses: BTreeSet<&'a str>,
+    /// Output store paths of installed packages.
+    out_paths: BTreeSet<&'a str>,
+}
+
+/// Maps repology name to its installed versions.
+type KnownVersions<'a> = BTreeMap<&'a str, KnownVersion<'a>>;
+
+/// Writes 'v' as pretty-printed JSON to 'path' if set.
+fn dump_json<T: serde::Serialize>(path: &Option<String>, v: &T) -> Result<(), OldeError> {
+    if let Some(path) = path {
+        log::info!("Writing {path:?}");
+        std::fs::write(path, serde_json::to_vec_pretty(v)?)?;
+    }
+    Ok(())
+}
+
+/// Returns true if 'pname' at installed 'version' plausibly belongs to
+/// repology package 'rp': versions share major component or 'pname'
+/// and repology project name (without namespace prefix) contain one
+/// another. Guards against same 'visiblename' of unrelated projects.
+fn plausible_match(pname: &str, version: &str, rp: &repology::Package) -> bool {
+    if rp
+        .version
+        .as_deref()
+        .is_some_and(|v| same_major(version, v))
+    {
+        return true;
+    }
+    let project = rp
+        .repology_name
+        .split_once(':')
+        .map_or(rp.repology_name.as_str(), |(_, n)| n)
+        .to_lowercase();
+    let pname = pname.to_lowercase();
+    pname.contains(&project) || project.contains(&pname)
+}
+
+/// Returns external tools run by code paths enabled in 'config'.
+/// 'nix-instantiate' is only a fallback for non-flake systems and is
+/// not required.
+fn required_tools<'a>(config: &Config, http_opts: &'a http::HttpOptions) -> Vec<&'a str> {
+    let local = !config.repology_only;
+    let compares = config.compare_nixpkgs.is_some();
+    let resolves = |n: &Option<String>| n.as_deref().is_some_and(is_flake_reference);
+    // Detects the channel of '<nixpkgs>' or of system flake's input.
+    let detects_channel =
+        config.stable_channel && config.repology_repo.is_none() && config.nixpkgs.is_none();
+    let evaluates_installed = local && config.closure_json.is_none() && config.generation.is_none();
+    let evaluates_available = (local && !config.no_available) || compares;
+
+    let mut tools: Vec<&str> = Vec::new();
+    if resolves(&config.nixpkgs)
+        || resolves(&config.compare_nixpkgs)
+        || detects_channel
+        || evaluates_installed
+        || evaluates_available
+        || (local && (config.generation.is_some() || config.with_size))
+    {
+        tools.push("nix");
+    }
+    if local && config.generation.is_some() {
+        tools.push("nix-store");
+    }
+    if evaluates_available {
+        tools.push("nix-env");
+    }
+    let fetches = config.repology_dump.is_none() || (local && config.with_maintainers);
+    if fetches && !compares {
+        tools.extend(http::required_tool(http_opts));
+    }
+    tools
+}
+
+/// Returns packages of a successful task. Failed task is recorded in
+/// 'unavailable' and yields no packages.
+fn or_unavailable<P>(
+    res: Result<BTreeSet<P>, OldeError>,
+    name: &'static str,
+    unavailable: &mut Vec<(&'static str, OldeError)>,
+) -> BTreeSet<P> {
+    res.unwrap_or_else(|e| {
+        unavailable.push((name, e));
+        BTreeSet::new()
+    })
+}
+
+/// Fetches installed, available and repology packages and finds
+/// outdated ones. Progress is reported on stderr unless 'config.quiet'
+/// is set.
+pub fn run(config: Config) -> Result<Report, OldeError> {
+    let ctx = &CmdContext::new(config.dry_run, config.command_timeout);
+    let http_opts = http::HttpOptions::new(
+        config.proxy.clone(),
+        config.curl_path.clone(),
+        config.curl_args.clone(),
+    );
+
+    // Missing binaries fail fast before any of them is run.
+    if !config.dry_run {
+        check_tools(&required_tools(&config, &http_opts))?;
+    }
+
+    let nixpkgs: Option<String> = config
+        .nixpkgs
+        .as_deref()
+        .map(|n| resolve_nixpkgs(n, ctx))
+        .transpose()?;
+    let compare_nixpkgs: Option<String> = config
+        .compare_nixpkgs
+        .as_deref()
+        .map(|n| resolve_nixpkgs(n, ctx))
+        .transpose()?;
+    let nixos_flakes: Vec<Flake> = match config.flake.as_slice() {
+        [] => vec![Flake::new(&None, &config.host, &config.system)?],
+        fs => fs
+            .iter()
+            .map(|f| Flake::new(&Some(f.clone()), &config.host, &config.system))
+            .collect::<Result<_, _>>()?,
+    };
+    let nixos_flake = &nixos_flakes[0];
+    let home_manager_flake = config
+        .home_manager
+        .as_deref()
+        .map(|hm| Flake::new_home_manager(hm, &config.system))
+        .transpose()?;
+    let profile: Option<String> = config.profile.as_ref().map(|p| match p.strip_prefix("~/") {
+        None => p.clone(),
+        Some(rest) => format!("{}/{rest}", std::env::var("HOME").unwrap_or_default()),
+    });
+
+    let ignored: BTreeSet<String> = match &config.ignore_file {
+        None => BTreeSet::new(),
+        Some(path) => read_ignore_file(path)?,
+    };
+    let excludes = compile_excludes(&config.exclude)?;
+
+    let repology_opts = repology::FetchOptions {
+        url: config.repology_url.clone(),
+        dump: config.repology_dump.clone(),
+        repo: channel::repology_repo(
+            &config.repology_repo,
+            config.stable_channel,
+            &nixpkgs,
+            nixos_flake,
+            ctx,
+        )?,
+        retries: config.repology_retries,
+        cache: config
+            .cache_dir
+            .as_ref()
+            .map(|d| Cache::new(d, config.cache_ttl)),
+        min_fetch_interval: config.repology_interval,
+        concurrency: config.repology_concurrency.get(),
+        only: config.only.clone(),
+        all: config.all_repology_projects,
+        pre_release: config.include_pre_release,
+        http: http_opts,
+        user_agent_suffix: config.user_agent_suffix.clone(),
+    };
+
+    let nix_args = available::NixArgs {
+        packages_config: config.packages_config.clone(),
+        args: config.nix_args.clone(),
+        argstrs: config.nix_argstrs.clone(),
+    };
+
+    // Evaluation results are keyed by store paths and never go stale.
+    let eval_cache = config
+        .cache_dir
+        .as_ref()
+        .map(|d| Cache::new(d, config.cache_ttl));
+
+    // Per-task durations for '--timing-json'.
+    let timings: Mutex<BTreeMap<&'static str, Duration>> = Mutex::new(BTreeMap::new());
+
+    // Typos in configuration names fail fast instead of after a long
+    // evaluation.
+    let evaluates_installed = config.closure_json.is_none() && config.generation.is_none();
+    if !config.repology_only && evaluates_installed {
+        for f in &nixos_flakes {
+            installed::check_flake_configuration(f, ctx)?;
+        }
+        if let Some(hm) = &home_manager_flake {
+            installed::check_flake_configuration(hm, ctx)?;
+        }
+    }
+
+    // Repository matching system's nixpkgs channel. '--stable-channel'
+    // and '--compare-nixpkgs' don't need the check.
+    let check_channel = !config.stable_channel && compare_nixpkgs.is_none() && !config.dry_run;
+    let mut detected_repo: Option<String> = None;
+    let mut nixpkgs_revision: Option<String> = None;
+
+    let (r, i, a) = {
+        let mut r: Result<BTreeSet<repology::Package>, OldeError> = Ok(BTreeSet::new());
+        let mut i: Result<BTreeSet<installed::Package>, OldeError> = Ok(BTreeSet::new());
+        let mut a: Result<BTreeSet<available::Package>, OldeError> = Ok(BTreeSet::new());
+
+        // If an error occured in other (faster) threads then this
+        // flag is raised to signal cancellation.
+        let cancel_flag = &AtomicBool::new(false);
+        // '--dry-run' fails all commands but still needs to print
+        // commands of every task. '--best-effort' needs results of
+        // every task that can succeed.
+        let cancel = || {
+            if !config.dry_run && !config.best_effort {
+                cancel_flag.store(true, Ordering::Relaxed);
+            }
+        };
+        let poll_cancel = || cancel_flag.load(Ordering::Relaxed);
+        let task_ctx = &ctx.with_cancel(&poll_cancel);
+        let record_timing = |p: &TaskProgress<'static>| {
+            let mut ts = timings.lock().expect("timings lock");
+            ts.insert(p.name, p.elapsed());
+        };
+
+        // Each of threads is somewhat slow to proceed:
+        // - Repology thread is network-bound
+        // - Installed and available threads are CPU-bound and
+        //   memory-hungry. '--jobs' limits their concurrency.
+        let jobs = &JobSlots::new(config.jobs);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                // '--compare-nixpkgs' replaces repology with packages
+                // of another nixpkgs.
+                if compare_nixpkgs.is_some() {
+                    let _slot = jobs.acquire();
+                    if poll_cancel() {
+                        r = Err(OldeError::Canceled(String::from("reference evaluation")));
+                        return;
+                    }
+                    let mut p = TaskProgress::new("reference", config.quiet);
+                    r = available::get_packages(
+                        &compare_nixpkgs,
+                        &nixos_flakes,
+                        &nix_args,
+                        eval_cache.as_ref(),
+                        task_ctx,
+                    )
+                    .map(|ps| repology::from_available(&ps));
+                    if r.is_err() {
+                        cancel();
+                        p.fail();
+                    }
+                    record_timing(&p);
+                    return;
+                }
+                let mut p = TaskProgress::new("repology", config.quiet);
+                r = repology::get_packages(&repology_opts, &mut p, task_ctx);
+                if r.is_err() {
+                    cancel();
+                    p.fail();
+                }
+                record_timing(&p);
+            });
+            // '--repology-only' does not need local packages.
+            if config.repology_only {
+                return;
+            }
+            s.spawn(|| {
+                let _slot = jobs.acquire();
+                if poll_cancel() {
+                    i = Err(OldeError::Canceled(String::from("installed evaluation")));
+                    return;
+                }
+                let mut p = TaskProgress::new("installed", config.quiet);
+                i = match (&config.closure_json, config.generation) {
+                    (Some(path), _) => installed::get_closure_packages(path),
+                    (None, Some(g)) => installed::get_generation_packages(g, task_ctx),
+                    (None, None) => installed::get_packages(
+                        &nixpkgs,
+                        &nixos_flakes,
+                        home_manager_flake.as_ref(),
+                        profile.as_deref(),
+                        eval_cache.as_ref(),
+                        task_ctx,
+                    ),
+                };
+                if i.is_err() {
+                    cancel();
+                    p.fail();
+                }
+                record_timing(&p);
+            });
+            // '--no-available' derives available packages from
+            // installed ones.
+            if config.no_available {
+                return;
+            }
+            s.spawn(|| {
+                let _slot = jobs.acquire();
+                if poll_cancel() {
+                    a = Err(OldeError::Canceled(String::from("available evaluation")));
+                    return;
+                }
+                let mut p = TaskProgress::new("available", config.quiet);
+                a = available::get_packages(
+                    &nixpkgs,
+                    &nixos_flakes,
+                    &nix_args,
+                    eval_cache.as_ref(),
+                    task_ctx,
+                );
+                if a.is_err() {
+                    cancel();
+                    p.fail();
+                }
+                // Sanity check of repology repository choice. Cheap
+                // once nixpkgs is fetched by evaluation above.
+                if a.is_ok() && check_channel {
+                    detected_repo = channel::system_repology_repo(&nixpkgs, nixos_flake, task_ctx)
+                        .unwrap_or_else(|e| {
+                            log::debug!("Failed to detect nixpkgs channel: {e}");
+                            None
+                        });
+                }
+                if a.is_ok() {
+                    nixpkgs_revision =
+                        channel::system_nixpkgs_revision(&nixpkgs, nixos_flake, task_ctx)
+                            .unwrap_or_else(|e| {
+                                log::debug!("Failed to detect nixpkgs revision: {e}");
+                                None
+                            });
+                }
+                record_timing(&p);
+            });
+        });
+
+        (r, i, a)
+    };
+    if !config.quiet {
+        eprintln!();
+    }
+
+    // Failures are expected: no commands were run.
+    if config.dry_run {
+        return Ok(Report::default());
+    }
+
+    // With '--best-effort' failed sources are replaced with empty
+    // ones unless all of them failed.
+    let mut unavailable: Vec<(&'static str, OldeError)> = Vec::new();
+    let (r, i, a) = if config.best_effort && (r.is_ok() || i.is_ok() || a.is_ok()) {
+        (
+            Ok(or_unavailable(r, "repology", &mut unavailable)),
+            Ok(or_unavailable(i, "installed", &mut unavailable)),
+            Ok(or_unavailable(a, "available", &mut unavailable)),
+        )
+    } else {
+        (r, i, a)
+    };
+
+    // Report all encountered errors
+    if r.is_err() || i.is_err() || a.is_err() {
+        let mut errs = Vec::new();
+        if r.is_err() {
+            errs.push(r.err().unwrap())
+        }
+        if i.is_err() {
+            errs.push(i.err().unwrap())
+        }
+        if a.is_err() {
+            errs.push(a.err().unwrap())
+        }
+
+        return Err(OldeError::MultipleErrors(errs));
+    }
+    let (repology_ps, installed_ps, available_ps) = (r?, i?, a?);
+    let available_ps = match config.no_available {
+        true => available::from_installed(&installed_ps),
+        false => available_ps,
+    };
+
+    if config.repology_only {
+        return Ok(Report {
+            repology: repology_ps
+                .into_iter()
+                .filter(|p| p.status.as_deref() == Some("outdated"))
+                .map(|p| RepologyPackage {
+                    repology_name: p.repology_name,
+                    name: p.name,
+                    version: p.version,
+                    latest: p.latest,
+                })
+                .collect(),
+            timings: timings.into_inner().expect("timings lock"),
+            ..Report::default()
+        });
+    }
+
+    dump_json(&config.dump_installed, &installed_ps)?;
+    dump_json(&config.dump_available, &available_ps)?;
+
+    // Installed packages not found in 'available'. Should be always empty.
+    // The exceptions are intermediate derivations for scripts and during
+    // bootstrap.
+    let mut missing_available: Vec<&str> = Vec::new();
+
+    // Packages not found in Repology database. Usually a package rename.
+    let mut missing_repology: Vec<(&str, &str, &str)> = Vec::new();
+
+    let mut known_versions: KnownVersions = BTreeMap::new();
+
+    // Installed packages with at least one attribute matching
+    // '--attr-prefix' filter.
+    let mut installed_in_scope: usize = 0;
+
+    // Index 'available' by 'name' and 'repology' by 'pname'. Group
+    // entries keep 'BTreeSet' order to get deterministic output.
+    let mut available_by_name: HashMap<&str, Vec<&available::Package>> = HashMap::new();
+    for ap in &available_ps {
+        available_by_name.entry(&ap.name).or_default().push(ap);
+    }
+    let mut available_by_pname: HashMap<&str, Vec<&available::Package>> = HashMap::new();
+    for ap in &available_ps {
+        available_by_pname.entry(&ap.pname).or_default().push(ap);
+    }
+    let mut repology_by_name: HashMap<&str, Vec<&repology::Package>> = HashMap::new();
+    for rp in &repology_ps {
+        repology_by_name.entry(&rp.name).or_default().push(rp);
+    }
+
+    // Map installed => available => repology. Sometimes mapping is
+    // one-to-many. Unavailable installed or available packages break
+    // the chain: skip the mapping instead of reporting every package
+    // as missing. Unavailable repology only skips repology matches.
+    let is_unavailable = |name: &str| unavailable.iter().any(|(n, _)| *n == name);
+    let repology_available = !is_unavailable("repology");
+    let mapped_ps = if is_unavailable("installed") || is_unavailable("available") {
+        &BTreeSet::new()
+    } else {
+        &installed_ps
+    };
+    for lp in mapped_ps {
+        let mut found_in_available = false;
+        let mut in_scope = false;
+
+        let aps = available_by_name.get(lp.name.as_str());
+        for ap in aps.map(Vec::as_slice).unwrap_or_default() {
+            found_in_available = true;
+
+            if !matches_attr_prefix(&ap.attribute, &config.attr_prefix) {
+                continue;
+            }
+            in_scope = true;
+
+            let mut found_on_repology = false;
+            let rps = repology_by_name.get(ap.pname.as_str());
+            for rp in rps.map(Vec::as_slice).unwrap_or_default() {
+                if config.strict_pname_match && !plausible_match(&ap.pname, &lp.version, rp) {
+                    log::debug!(
+                        "Rejected match of {} {} to {} {:?}: unrelated name and version",
+                        ap.attribute,
+                        lp.version,
+                        rp.repology_name,
+                        rp.version
+                    );
+                    continue;
+                }
+                found_on_repology = true;
+
+                let status = rp.status.as_deref();
+                if !config.include_status.is_empty()
+                    && !config
+                        .include_status
+                        .iter()
+                        .any(|s| Some(s.as_str()) == status)
+                {
+                    continue;
+                }
+
+                let kv = known_versions.entry(&rp.repology_name).or_default();
+                kv.latest = rp.latest.as_deref();
+                kv.versions.insert(&lp.version);
+                kv.attributes.insert(&ap.attribute);
+                kv.installed.insert((&ap.attribute, &lp.version));
+                kv.statuses.extend(status);
+                kv.out_paths.extend(lp.out_paths.iter().map(String::as_str));
+            }
+            if !found_on_repology && repology_available {
+                missing_repology.push((&ap.attribute, &ap.pname, &lp.name));
+            }
+        }
+        if !found_in_available {
+            // Same 'pname' with another version usually means an
+            // override or a package from another nixpkgs.
+            let similar = lp
+                .pname
+                .as_deref()
+                .and_then(|pn| available_by_pname.get(pn))
+                .map(|aps| aps.iter().map(|ap| ap.name.as_str()).collect::<Vec<_>>());
+            match similar {
+                Some(names) => log::debug!(
+                    "{} is missing in available packages, but {:?} are: overridden or from another nixpkgs?",
+                    lp.name,
+                    names
+                ),
+                None => log::debug!("{} is missing in available packages", lp.name),
+            }
+            missing_available.push(&lp.name);
+        }
+        // Without filters all installed packages are counted.
+        if in_scope || config.attr_prefix.is_empty() {
+            installed_in_scope += 1;
+        }
+    }
+
+    let mut outdated_packages: Vec<OutdatedPackage> = Vec::new();
+    let mut suppressed: usize = 0;
+    // Versions of two nixpkgs are always ordered the nix way. Repology
+    // versions only have a known order when numeric.
+    let source = match compare_nixpkgs {
+        Some(_) => Source::Reference,
+        None => Source::Repology,
+    };
+    let order = |a: &str, b: &str| match source {
+        Source::Reference => Some(compare_nix(a, b)),
+        Source::Repology => compare(a, b),
+    };
+    for (rn, kv) in &known_versions {
+        let (olv, vs) = (kv.latest, &kv.versions);
+        // Do not print outdated versions if there is use of most recet
+        // package. Reference nixpkgs might be older than system's one.
+        let up_to_date = olv.is_some_and(|lv| {
+            let nlv = normalize(lv);
+            vs.iter().any(|v| normalize(v) == nlv)
+                || (source == Source::Reference
+                    && vs.iter().any(|v| order(v, lv).is_some_and(|c| c.is_ge())))
+        });
+        if up_to_date && !config.show_all {
+            continue;
+        }
+        if ignored.contains(*rn) || excludes.iter().any(|re| re.is_match(rn)) {
+            if !up_to_date {
+                suppressed += 1;
+            }
+            continue;
+        }
+        // Newer than the latest known version: repology does not know
+        // the version yet or nixpkgs uses a pre-release.
+        let ahead =
+            olv.is_some_and(|lv| vs.iter().any(|v| order(v, lv).is_some_and(|c| c.is_gt())));
+        if ahead && !up_to_date && !config.show_ahead {
+            continue;
+        }
+        // Up to date packages have no version gap.
+        let severity = match up_to_date {
+            true => Severity::Unknown,
+            false => severity_of_set(vs.iter().copied(), olv),
+        };
+        if !up_to_date && severity.max(Severity::Patch) < config.min_severity {
+            continue;
+        }
+        let state = match (up_to_date, ahead) {
+            (true, _) => State::Current,
+            (false, true) => State::Ahead,
+            (false, false) => State::Outdated,
+        };
+        outdated_packages.push(OutdatedPackage {
+            repology_name: rn.to_string(),
+            attribute: kv.attributes.iter().map(|a| a.to_string()).collect(),
+            repology_version: olv.map(|v| v.to_string()),
+            nixpkgs_version: vs.iter().map(|v| v.to_string()).collect(),
+            installed: kv
+                .installed
+                .iter()
+                .map(|(a, v)| InstalledVersion {
+                    attribute: a.to_string(),
+                    version: v.to_string(),
+                })
+                .collect(),
+            severity,
+            status: kv.statuses.iter().map(|s| s.to_string()).collect(),
+            state,
+            source,
+            repology_url: (config.repology_urls && compare_nixpkgs.is_none())
+                .then(|| repology::project_url(&config.repology_url, rn)),
+            closure_size_bytes: None,
+            up_to_date: config.show_all.then_some(up_to_date),
+            maintainers: None,
+        });
+    }
+
+    // A single query for all packages: it is slow on large closures.
+    if config.with_size && !outdated_packages.is_empty() {
+        let paths_of = |rn: &str| known_versions.get(rn).map(|kv| &kv.out_paths);
+        let paths: BTreeSet<&str> = outdated_packages
+            .iter()
+            .filter_map(|p| paths_of(&p.repology_name))
+            .flatten()
+            .copied()
+            .collect();
+        match installed::get_closure_sizes(&paths, ctx) {
+            Ok(sizes) => {
+                for p in &mut outdated_packages {
+                    p.closure_size_bytes = paths_of(&p.repology_name)
+                        .into_iter()
+                        .flatten()
+                        .filter_map(|op| sizes.get(*op).copied())
+                        .max();
+                }
+            }
+            Err(e) => log::warn!("Failed to query closure sizes: {e}"),
+        }
+    }
+    // Up to date packages need no attention.
+    if config.with_maintainers && compare_nixpkgs.is_none() {
+        let projects: Vec<&str> = outdated_packages
+            .iter()
+            .filter(|p| p.is_outdated())
+            .map(|p| p.repology_name.as_str())
+            .collect();
+        let mut maintainers = repology::get_maintainers(&repology_opts, &projects, ctx)?;
+        for p in &mut outdated_packages {
+            p.maintainers = maintainers.remove(&p.repology_name);
+        }
+    }
+    missing_available.sort();
+    missing_repology.sort();
+    missing_repology.dedup();
+
+    Ok(Report {
+        outdated: outdated_packages,
+        installed: installed_in_scope,
+        suppressed,
+        missing_available: missing_available.iter().map(|n| n.to_string()).collect(),
+        missing_repology: missing_repology
+            .iter()
+            .map(|(a, p, n)| MissingRepology {
+                attribute: a.to_string(),
+                pname: p.to_string(),
+                name: n.to_string(),
+            })
+            .collect(),
+        attributes: known_versions
+            .iter()
+            .map(|(rn, kv)| {
+                let ats = kv.attributes.iter().map(|a| a.to_string()).collect();
+                (rn.to_string(), ats)
+            })
+            .collect(),
+        repology: Vec::new(),
+        repology_repo: repology_opts.repo.clone(),
+        detected_repo,
+        nixpkgs_revision,
+        unavailable,
+        timings: timings.into_inner().expect("timings lock"),
+    })
+}
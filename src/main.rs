@@ -1,189 +1,380 @@
-// TODO: can we move it out to Cargo.toml? Or a separate file?
-mod cmd;
-mod error;
-mod flake;
+mod config;
+mod diff;
 mod opts;
-mod progress;
-
-// package loading modules
-mod available;
-mod installed;
-mod repology;
+mod output;
 
 use std::collections::BTreeMap;
-use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::io::IsTerminal;
 use std::io::Write;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+use nix_olde::*;
 
-use crate::error::*;
-use crate::flake::*;
+use crate::config::*;
+use crate::diff::*;
 use crate::opts::*; // TODO: how to avoid explicit import?
-use crate::progress::*;
+use crate::output::*;
 
-fn main() -> Result<(), OldeError> {
-    let o = Opts::parse();
-    env_logger::Builder::new()
-        .format(|buf, record| writeln!(buf, "{}: {}", record.level(), record.args()))
-        .filter_level(o.verbose.log_level_filter())
-        .init();
+/// Exit code used by '--exit-code' when outdated packages are found.
+/// Tool failures are reported with exit code 1.
+const EXIT_CODE_OUTDATED: u8 = 2;
 
-    let nixos_flake = Flake::new(&o.flake);
-
-    let (r, i, a) = {
-        let mut r: Result<BTreeSet<repology::Package>, OldeError> = Ok(BTreeSet::new());
-        let mut i: Result<BTreeSet<installed::Package>, OldeError> = Ok(BTreeSet::new());
-        let mut a: Result<BTreeSet<available::Package>, OldeError> = Ok(BTreeSet::new());
-
-        // If an error occured in other (faster) threads then this
-        // flag is raised to signal cancellation.
-        let cancel_flag = &AtomicBool::new(false);
-        let cancel = || {
-            cancel_flag.store(true, Ordering::Relaxed);
-        };
-        let poll_cancel = || cancel_flag.load(Ordering::Relaxed);
-
-        // Each of threads is somewhat slow to proceed:
-        // - Repology thread is network-bound
-        // - Installed and available threads are CPU-bound
-        std::thread::scope(|s| {
-            s.spawn(|| {
-                let mut p = TaskProgress::new("repology");
-                r = repology::get_packages(&poll_cancel);
-                if r.is_err() {
-                    cancel();
-                    p.fail();
-                }
-            });
-            s.spawn(|| {
-                let mut p = TaskProgress::new("installed");
-                i = installed::get_packages(&o.nixpkgs, &nixos_flake);
-                if i.is_err() {
-                    cancel();
-                    p.fail();
-                }
-            });
-            s.spawn(|| {
-                let mut p = TaskProgress::new("available");
-                a = available::get_packages(&o.nixpkgs, &nixos_flake);
-                if a.is_err() {
-                    cancel();
-                    p.fail();
-                }
-            });
-        });
-
-        (r, i, a)
-    };
-    eprintln!();
+/// Opens '--output' file or stdout.
+fn open_output(path: &Option<String>) -> Result<Box<dyn Write>, OldeError> {
+    Ok(match path {
+        None => Box::new(std::io::stdout().lock()),
+        Some(path) => Box::new(std::io::BufWriter::new(std::fs::File::create(path)?)),
+    })
+}
 
-    // Report all encountered errors
-    if r.is_err() || i.is_err() || a.is_err() {
-        let mut errs = Vec::new();
-        if r.is_err() {
-            errs.push(r.err().unwrap())
-        }
-        if i.is_err() {
-            errs.push(i.err().unwrap())
-        }
-        if a.is_err() {
-            errs.push(a.err().unwrap())
+fn main() -> ExitCode {
+    let matches = Opts::command().get_matches();
+    let o = Opts::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let mut error_format = o.error_format;
+    let r = with_config(o, &matches).and_then(|o| {
+        error_format = o.error_format;
+        run(o)
+    });
+    match r {
+        Ok(c) => c,
+        Err(e) => {
+            print_error(&e, error_format);
+            ExitCode::FAILURE
         }
+    }
+}
 
-        return Err(OldeError::MultipleErrors(errs));
+fn print_error(e: &OldeError, error_format: ErrorFormat) {
+    match error_format {
+        ErrorFormat::Text => eprintln!("Error: {e}"),
+        ErrorFormat::Json => eprintln!("{}", e.to_json()),
     }
-    let (repology_ps, installed_ps, available_ps) = (r?, i?, a?);
+}
 
-    // Installed packages not found in 'available'. Should be always empty.
-    // The exceptions are intermediate derivations for scripts and during
-    // bootstrap.
-    let mut missing_available: Vec<&str> = Vec::new();
+/// Adds options from config file that are not passed on command line
+/// ('cli').
+fn with_config(o: Opts, cli: &clap::ArgMatches) -> Result<Opts, OldeError> {
+    let config_path = match &o.config {
+        Some(p) => Some(PathBuf::from(p)),
+        None if o.no_config => None,
+        None => default_config_path().filter(|p| p.exists()),
+    };
+    Ok(match config_path {
+        None => o,
+        Some(path) => {
+            let mut args: Vec<OsString> = std::env::args_os().take(1).collect();
+            args.extend(config_args(&path, cli)?.into_iter().map(OsString::from));
+            args.extend(std::env::args_os().skip(1));
+            Opts::parse_from(args)
+        }
+    })
+}
 
-    // Packages not found in Repology database. Usually a package rename.
-    let mut missing_repology: Vec<(&str, &str)> = Vec::new();
+fn run(o: Opts) -> Result<ExitCode, OldeError> {
+    let log_format = o.log_format;
+    env_logger::Builder::new()
+        .format(move |buf, record| match log_format {
+            LogFormat::Text => writeln!(buf, "{}: {}", record.level(), record.args()),
+            LogFormat::Json => {
+                let timestamp = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|d| d.as_secs_f64())
+                    .unwrap_or(0.0);
+                let entry = serde_json::json!({
+                    "level": record.level().as_str(),
+                    "timestamp": timestamp,
+                    "message": record.args().to_string(),
+                });
+                writeln!(buf, "{entry}")
+            }
+        })
+        .filter_level(o.log_level_filter())
+        .init();
 
-    let mut known_versions: BTreeMap<&str, (&Option<String>, BTreeSet<&str>, BTreeSet<&str>)> =
-        BTreeMap::new();
+    if let Some(shell) = o.generate_completions {
+        let mut cmd = Opts::command();
+        let name = cmd.get_name().to_string();
+        clap_complete::generate(shell, &mut cmd, name, &mut std::io::stdout().lock());
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if o.json_envelope && o.format != OutputFormat::JsonArray {
+        Opts::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "'--json-envelope' requires '--format json-array'",
+            )
+            .exit();
+    }
+
+    if o.print_schema {
+        serde_json::to_writer_pretty(std::io::stdout().lock(), &outdated_schema())?;
+        println!();
+        return Ok(ExitCode::SUCCESS);
+    }
 
-    // Map installed => available => repology. Sometimes mapping is
-    // one-to-many.
-    for lp in &installed_ps {
-        let mut found_in_available = false;
+    if let Some([old, new]) = o.diff.as_deref() {
+        let es = diff_reports(read_report(old)?, read_report(new)?);
+        let mut out = open_output(&o.output)?;
+        write_diff(&mut out, o.format, &es, o.pretty)?;
+        out.flush()?;
+        if !o.quiet {
+            let count = |c: Change| es.iter().filter(|e| e.change == c).count();
+            eprintln!();
+            eprintln!(
+                "{} newly outdated, {} fixed, {} changed packages.",
+                count(Change::New),
+                count(Change::Fixed),
+                count(Change::Changed)
+            );
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
 
-        for ap in &available_ps {
-            if lp.name != ap.name {
-                continue;
+    if let Some(interval) = o.watch {
+        // Human-readable reports on a terminal are redrawn in place.
+        // Other reports are appended.
+        let redraw = o.output.is_none()
+            && std::io::stdout().is_terminal()
+            && [OutputFormat::Plain, OutputFormat::Table].contains(&o.format);
+        loop {
+            if redraw {
+                print!("\x1b[2J\x1b[H");
             }
-            found_in_available = true;
-
-            let mut found_on_repology = false;
-            for rp in &repology_ps {
-                if ap.pname != rp.name {
-                    continue;
-                }
-                found_on_repology = true;
-
-                match known_versions.get_mut(&rp.repology_name as &str) {
-                    None => {
-                        let mut vs: BTreeSet<&str> = BTreeSet::new();
-                        vs.insert(&lp.version);
-
-                        let mut ats: BTreeSet<&str> = BTreeSet::new();
-                        ats.insert(&ap.attribute);
-                        known_versions.insert(&rp.repology_name, (&rp.latest, vs, ats));
-                    }
-                    Some((_, ref mut vs, ref mut ats)) => {
-                        vs.insert(&lp.version);
-                        ats.insert(&ap.attribute);
-                    }
-                }
+            if let Err(e) = report(&o) {
+                print_error(&e, o.error_format);
             }
-            if !found_on_repology {
-                missing_repology.push((&ap.pname, &lp.name));
+            if !o.quiet {
+                eprintln!();
+                eprintln!("Next run in {interval:?}.");
             }
+            std::thread::sleep(interval);
+        }
+    }
+
+    report(&o)
+}
+
+/// Runs the pipeline once and prints its report.
+fn report(o: &Opts) -> Result<ExitCode, OldeError> {
+    let started = Instant::now();
+    let nix_args = |values: &[String]| {
+        nix_arg_pairs(values).unwrap_or_else(|e| {
+            Opts::command()
+                .error(clap::error::ErrorKind::InvalidValue, e)
+                .exit()
+        })
+    };
+
+    let mut config = Config::default();
+    config.nixpkgs = o.nixpkgs.clone();
+    config.flake = o.flake.clone();
+    config.host = o.host.clone();
+    config.home_manager = o.home_manager.clone();
+    config.profile = o.profile.clone();
+    config.repology_url = o.repology_url.clone();
+    config.repology_repo = o.repology_repo.clone();
+    config.stable_channel = o.stable_channel;
+    config.repology_retries = o.repology_retries;
+    config.repology_dump = o.repology_dump.clone();
+    config.repology_interval = Duration::from_millis(o.repology_interval_ms);
+    config.repology_concurrency = o.repology_concurrency;
+    config.cache_dir = o.cache_dir.clone();
+    config.cache_ttl = Duration::from_secs(o.cache_ttl);
+    config.compare_nixpkgs = o.compare_nixpkgs.clone();
+    config.jobs = o.jobs;
+    config.command_timeout = o.command_timeout.map(Duration::from_secs);
+    config.dry_run = o.dry_run;
+    config.quiet = o.quiet;
+    config.ignore_file = o.ignore_file.clone();
+    config.exclude = o.exclude.clone();
+    config.attr_prefix = o.attr_prefix.clone();
+    config.min_severity = o.min_severity;
+    config.include_status = o.include_status.clone();
+    config.show_ahead = o.show_ahead;
+    config.include_pre_release = o.include_pre_release;
+    config.strict_pname_match = o.strict_pname_match;
+    config.show_all = o.show_all;
+    config.repology_urls = !o.no_repology_url;
+    config.with_size = o.with_size;
+    config.with_maintainers = o.with_maintainers;
+    config.packages_config = o.packages_config.clone();
+    config.nix_args = nix_args(&o.nix_arg);
+    config.nix_argstrs = nix_args(&o.nix_argstr);
+    config.repology_only = o.repology_only || o.fetch_only;
+    config.only = match &o.list_attributes {
+        Some(name) => vec![name.clone()],
+        None => o.only.clone(),
+    };
+    config.best_effort = o.best_effort;
+    config.all_repology_projects = o.show_missing_repology || o.show_all;
+    config.system = o.system.clone();
+    config.no_available = o.no_available;
+    config.closure_json = o.closure_json.clone();
+    config.generation = o.generation;
+    config.dump_installed = o.dump_installed.clone();
+    config.dump_available = o.dump_available.clone();
+    config.proxy = o.proxy.clone();
+    config.curl_path = o.curl_path.clone();
+    config.curl_args = o.curl_arg.clone();
+    config.user_agent_suffix = o.user_agent_suffix.clone();
+    let report = nix_olde::run(config)?;
+
+    // Failures are expected: no commands were run.
+    if o.dry_run {
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if o.fetch_only {
+        if !o.quiet {
+            eprintln!();
+            eprintln!(
+                "Repology data is cached in {}.",
+                o.cache_dir.as_deref().unwrap_or_default()
+            );
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if let Some(name) = &o.list_attributes {
+        let ats = report.attributes.get(name);
+        if ats.is_none() {
+            eprintln!("No installed attributes map to '{name}' repology project.");
+            return Ok(ExitCode::FAILURE);
         }
-        if !found_in_available {
-            missing_available.push(&lp.name);
+        let mut out = open_output(&o.output)?;
+        for a in ats.into_iter().flatten() {
+            writeln!(out, "{a}")?;
         }
+        out.flush()?;
+        return Ok(ExitCode::SUCCESS);
     }
 
-    let mut found_outdated: isize = 0;
-    for (rn, (olv, vs, ats)) in &known_versions {
-        if let Some(lv) = olv {
-            // Do not print outdated versions if there is use of most recet package
-            if vs.contains(lv as &str) {
-                continue;
-            }
+    if o.repology_only {
+        let mut out = open_output(&o.output)?;
+        write_repology(&mut out, o.format, &report.repology, o.pretty)?;
+        out.flush()?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    for (name, e) in &report.unavailable {
+        eprintln!(
+            "Warning: '{name}' data is unavailable, results depending on it are skipped: {e}"
+        );
+    }
+    if !report.unavailable.is_empty() {
+        eprintln!();
+    }
+
+    // Comparing stable nixpkgs against unstable repository reports
+    // nearly everything as outdated.
+    if let Some(detected) = &report.detected_repo {
+        if *detected != report.repology_repo {
+            eprintln!(
+                "Warning: system nixpkgs looks like '{detected}' but is compared against '{}'. \
+                 Consider '--repology-repo {detected}' or '--stable-channel'.",
+                report.repology_repo
+            );
+            eprintln!();
         }
-        println!(
-            "repology {} {:?} | nixpkgs {:?} {:?}",
-            rn,
-            (*olv).clone().unwrap_or("<none>".to_string()),
-            vs,
-            ats
+    }
+
+    let mut outdated_packages = report.outdated;
+    let installed_in_scope = report.installed;
+    let suppressed = report.suppressed;
+    let missing_available = report.missing_available;
+    sort_outdated(&mut outdated_packages, o.sort);
+
+    let mut out = open_output(&o.output)?;
+    let totals = Totals {
+        installed: installed_in_scope,
+        missing_available: missing_available.len(),
+        nixpkgs_revision: report.nixpkgs_revision.as_deref(),
+    };
+    // '--limit' keeps the first records after sorting. Metrics are
+    // always computed for all of them.
+    let shown = match (o.limit, o.format) {
+        (Some(n), f) if f != OutputFormat::Prometheus => {
+            &outdated_packages[..n.min(outdated_packages.len())]
+        }
+        _ => &outdated_packages[..],
+    };
+    // Revision heads the report to tell what it was computed against.
+    if let Some(rev) = &report.nixpkgs_revision {
+        if !o.quiet {
+            eprintln!("Checked nixpkgs revision: {rev}.");
+            eprintln!();
+        }
+    }
+    write_outdated(
+        &mut out,
+        o.format,
+        shown,
+        &totals,
+        o.pretty,
+        o.json_envelope,
+    )?;
+    out.flush()?;
+
+    if o.summary && !shown.is_empty() {
+        eprintln!();
+        write_summary(&mut std::io::stderr().lock(), shown, use_color(o.color))?;
+    }
+
+    if shown.len() < outdated_packages.len() && !o.quiet {
+        eprintln!();
+        eprintln!(
+            "Showing {} of {} outdated packages due to '--limit'.",
+            shown.len(),
+            outdated_packages.len()
         );
-        found_outdated += 1;
     }
 
-    if found_outdated > 0 {
+    let found_outdated = outdated_packages.iter().filter(|p| p.is_outdated()).count();
+    // Filters like '--attr-prefix' can leave no installed packages.
+    let ratio: f64 = match installed_in_scope {
+        0 => 0.0,
+        n => found_outdated as f64 * 100.0 / n as f64,
+    };
+    // Counts are meaningless without some of the sources.
+    let partial = !report.unavailable.is_empty();
+    if partial && !o.quiet {
+        eprintln!();
+        eprintln!("Outdated packages are not computed: some data sources are unavailable.");
+    } else if installed_in_scope == 0 && !o.quiet {
+        eprintln!();
+        eprintln!("0 installed packages matched filters.");
+    } else if found_outdated > 0 && !o.quiet {
         eprintln!();
-        let ratio: f64 = found_outdated as f64 * 100.0 / installed_ps.len() as f64;
         eprintln!(
-            "{} of {} ({:.2}%) installed packages are outdated according to https://repology.org.",
+            "{} of {} ({:.2}%) installed packages are outdated according to {}.",
             found_outdated,
-            installed_ps.len(),
-            ratio
+            installed_in_scope,
+            ratio,
+            o.compare_nixpkgs
+                .as_deref()
+                .unwrap_or("https://repology.org")
+        );
+    }
+
+    if suppressed > 0 && !o.quiet {
+        eprintln!();
+        eprintln!(
+            "{} outdated packages were suppressed by '--ignore-file' and '--exclude'.",
+            suppressed
         );
     }
 
-    missing_available.sort();
-    missing_repology.sort();
     if log::log_enabled!(log::Level::Debug) {
         eprintln!();
         eprintln!(
             "Installed packages missing in available list: {:?}",
             missing_available
         );
-    } else if !missing_available.is_empty() {
+    } else if !missing_available.is_empty() && !o.quiet {
         eprintln!();
         eprintln!(
             "Some installed packages are missing in available list: {}",
@@ -191,5 +382,56 @@ fn main() -> Result<(), OldeError> {
         );
         eprintln!("  Add '--verbose' to get it's full list.");
     }
-    Ok(())
+
+    if o.show_missing_repology && !report.missing_repology.is_empty() {
+        eprintln!();
+        eprintln!(
+            "Available packages missing on repology (renamed or new?): {}",
+            report.missing_repology.len()
+        );
+        for m in &report.missing_repology {
+            eprintln!("  {} (pname {:?}, name {:?})", m.attribute, m.pname, m.name);
+        }
+    }
+
+    if o.timing_json {
+        let tasks: BTreeMap<&str, f64> = report
+            .timings
+            .into_iter()
+            .map(|(n, d)| (n, d.as_secs_f64()))
+            .collect();
+        eprintln!();
+        eprintln!(
+            "{}",
+            serde_json::json!({
+                "tasks": tasks,
+                "total": started.elapsed().as_secs_f64(),
+            })
+        );
+    }
+
+    if let Some(path) = &o.audit_log {
+        append_audit_record(
+            path,
+            installed_in_scope,
+            found_outdated,
+            report.nixpkgs_revision.as_deref(),
+        )?;
+    }
+
+    // '--best-effort' output is incomplete: never report success.
+    if partial {
+        return Ok(ExitCode::FAILURE);
+    }
+    if o.exit_code && found_outdated > 0 {
+        return Ok(ExitCode::from(EXIT_CODE_OUTDATED));
+    }
+    if let Some(threshold) = o.fail_threshold {
+        if ratio > threshold {
+            eprintln!();
+            eprintln!("Outdated ratio {ratio:.2}% exceeds '--fail-threshold' of {threshold}%.");
+            return Ok(ExitCode::from(EXIT_CODE_OUTDATED));
+        }
+    }
+    Ok(ExitCode::SUCCESS)
 }
@@ -1,21 +1,576 @@
-pub use clap::Parser;
+use clap::builder::NonEmptyStringValueParser;
+use clap::{ArgAction, ValueEnum};
+pub use clap::{CommandFactory, FromArgMatches, Parser};
 use clap_verbosity_flag::{InfoLevel, Verbosity};
+use std::num::NonZeroUsize;
+use std::time::Duration;
+
+use nix_olde::Severity;
+
+/// Rendering of the outdated packages list.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum OutputFormat {
+    /// Human-readable 'repology ... | nixpkgs ...' line per package.
+    Plain,
+    /// One compact JSON object per line.
+    Ndjson,
+    /// Single pretty-printed JSON array of all packages.
+    #[value(alias = "json")]
+    JsonArray,
+    /// Aligned columns of names, attributes and versions.
+    Table,
+    /// Package counts as Prometheus metrics.
+    Prometheus,
+    /// Comma-separated values with a header row for spreadsheets.
+    Csv,
+    /// GitHub Actions warning annotation per package.
+    Github,
+    /// GitHub-flavored Markdown table for issues and pull requests.
+    Markdown,
+    /// SARIF log with a result per package for code scanning tools.
+    Sarif,
+}
+
+/// Rendering of a fatal error.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ErrorFormat {
+    /// Human-readable 'Error: ...' line.
+    Text,
+    /// JSON object with error 'kind', 'message' and details.
+    Json,
+}
+
+/// Rendering of log messages.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum LogFormat {
+    /// Human-readable 'LEVEL: message' line.
+    Text,
+    /// JSON object with 'level', 'timestamp' and 'message' per line.
+    Json,
+}
+
+/// Order of the outdated packages list.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum SortKey {
+    /// Alphabetically by repology name.
+    Name,
+    /// Largest version gap first.
+    Severity,
+    /// Alphabetically by the first nixpkgs attribute.
+    Attribute,
+}
+
+/// When to use colors in human-readable output.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ColorChoice {
+    /// Colors on a terminal unless NO_COLOR environment variable is set.
+    Auto,
+    Always,
+    Never,
+}
+
+/// Checks that 's' looks like an http(s) URL and adds trailing slash.
+fn parse_http_url(s: &str) -> Result<String, String> {
+    let rest = s
+        .strip_prefix("https://")
+        .or_else(|| s.strip_prefix("http://"))
+        .ok_or("expected http:// or https:// URL")?;
+    let host = rest.split('/').next().unwrap_or_default();
+    if host.is_empty() || s.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err("malformed URL".to_string());
+    }
+    match s.ends_with('/') {
+        true => Ok(s.to_string()),
+        false => Ok(format!("{s}/")),
+    }
+}
+
+/// Checks that 's' looks like a proxy URL supported by both 'curl'
+/// and 'reqwest'.
+fn parse_proxy_url(s: &str) -> Result<String, String> {
+    let (scheme, rest) = s
+        .split_once("://")
+        .ok_or("expected <scheme>://<host>[:<port>]")?;
+    if !["http", "https", "socks5", "socks5h"].contains(&scheme) {
+        return Err(format!(
+            "unsupported proxy scheme {scheme:?}, expected one of: http, https, socks5, socks5h"
+        ));
+    }
+    if rest.is_empty() || s.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err("malformed URL".to_string());
+    }
+    Ok(s.to_string())
+}
+
+/// Checks that 's' is safe to put into an HTTP header.
+fn parse_header_value(s: &str) -> Result<String, String> {
+    if s.trim().is_empty() {
+        return Err("empty value".to_string());
+    }
+    if s.chars().any(|c| c.is_control()) {
+        return Err("newlines and control characters are not allowed".to_string());
+    }
+    Ok(s.to_string())
+}
+
+/// Checks that 's' looks like a nix platform: '<arch>-<os>'.
+fn parse_system(s: &str) -> Result<String, String> {
+    match s.split_once('-') {
+        Some((arch, os))
+            if !arch.is_empty()
+                && !os.is_empty()
+                && s.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_".contains(c)) =>
+        {
+            Ok(s.to_string())
+        }
+        _ => Err("expected <arch>-<os> platform like 'aarch64-linux'".to_string()),
+    }
+}
+
+/// Splits '--nix-arg' / '--nix-argstr' values into (name, value)
+/// pairs. Names must be nix identifiers to avoid passing options to
+/// 'nix-env' instead of arguments.
+pub(crate) fn nix_arg_pairs(values: &[String]) -> Result<Vec<(String, String)>, String> {
+    values
+        .chunks(2)
+        .map(|nv| match nv {
+            [n, v]
+                if n.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                    && n.chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "_'-".contains(c)) =>
+            {
+                Ok((n.clone(), v.clone()))
+            }
+            _ => Err(format!(
+                "expected <NAME> <VALUE> with nix identifier as a name, got {nv:?}"
+            )),
+        })
+        .collect()
+}
+
+/// Parses positive interval like '90', '90s', '15m' or '1h'. Bare
+/// numbers are seconds.
+fn parse_interval(s: &str) -> Result<Duration, String> {
+    let (n, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        None => (s, 1),
+        Some(i) => match &s[i..] {
+            "s" => (&s[..i], 1),
+            "m" => (&s[..i], 60),
+            "h" => (&s[..i], 3600),
+            _ => return Err("expected <N>[s|m|h] interval like '15m'".to_string()),
+        },
+    };
+    match n.parse::<u64>() {
+        Ok(n) if n > 0 => Ok(Duration::from_secs(n * unit)),
+        _ => Err("expected positive <N>[s|m|h] interval like '15m'".to_string()),
+    }
+}
+
+/// Parses percentage in [0; 100] range.
+fn parse_percent(s: &str) -> Result<f64, String> {
+    let p: f64 = s.parse().map_err(|e| format!("{e}"))?;
+    if !(0.0..=100.0).contains(&p) {
+        return Err("expected a percentage between 0 and 100".to_string());
+    }
+    Ok(p)
+}
 
 /// A tool to show outdated packages in current system according to
 /// repology.org database.
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+// Options from config file precede command line options. Later
+// options win.
+#[command(author, version, about, long_about = None, args_override_self = true)]
 pub(crate) struct Opts {
-    /// Alternative path to <nixpkgs> location.
+    /// Alternative path to <nixpkgs> location or a flake reference like
+    /// 'github:NixOS/nixpkgs/nixos-24.11'.
     #[arg(short, long)]
     pub(crate) nixpkgs: Option<String>,
 
     /// Enable extra verbosity to report unexpected events,
     /// fetch progress and so on.
-    #[command(flatten)]
-    pub(crate) verbose: Verbosity<InfoLevel>,
+    #[arg(short, long, action = ArgAction::Count)]
+    pub(crate) verbose: u8,
 
-    /// Pass a system flake alternative to /etc/nixos default.
+    /// Decrease logging verbosity.
+    #[arg(short, long, action = ArgAction::Count)]
+    pub(crate) silent: u8,
+
+    /// Hide progress and summary lines on stderr leaving only the
+    /// outdated packages list and errors. Logging is not affected.
+    #[arg(short, long)]
+    pub(crate) quiet: bool,
+
+    /// Pass a system flake alternative to /etc/nixos default. Can be
+    /// passed multiple times to merge packages of several systems.
     #[arg(short, long)]
-    pub(crate) flake: Option<String>,
+    pub(crate) flake: Vec<String>,
+
+    /// Output format of the outdated packages list.
+    #[arg(long, value_enum, default_value_t = OutputFormat::Plain)]
+    pub(crate) format: OutputFormat,
+
+    /// Write outdated packages list to a file instead of stdout.
+    #[arg(short, long)]
+    pub(crate) output: Option<String>,
+
+    /// File with repology names to exclude from the report. One name
+    /// per line, '#' starts a comment.
+    #[arg(long)]
+    pub(crate) ignore_file: Option<String>,
+
+    /// Repology repository to compare against, like 'nix_stable_24_05'.
+    /// Defaults to 'nix_unstable'. Overrides '--stable-channel'.
+    #[arg(long, value_parser = NonEmptyStringValueParser::new())]
+    pub(crate) repology_repo: Option<String>,
+
+    /// Number of retries of a failed repology page fetch.
+    #[arg(long, default_value_t = 3)]
+    pub(crate) repology_retries: u32,
+
+    /// Directory to cache fetched repology pages and parsed nix
+    /// evaluation results in.
+    #[arg(long)]
+    pub(crate) cache_dir: Option<String>,
+
+    /// Maximum age of a repology cache entry in seconds to be reused.
+    #[arg(long, default_value_t = 3600)]
+    pub(crate) cache_ttl: u64,
+
+    /// Exit with code 2 when outdated packages are found. Failures
+    /// always exit with code 1.
+    #[arg(long)]
+    pub(crate) exit_code: bool,
+
+    /// Maximum number of concurrent 'nix' evaluations. Unlimited by
+    /// default. '--jobs 1' helps on low-memory machines.
+    #[arg(short, long)]
+    pub(crate) jobs: Option<NonZeroUsize>,
+
+    /// Also include packages of a home manager flake, like
+    /// '~/hm#user@host'. Attribute defaults to $USER.
+    #[arg(long)]
+    pub(crate) home_manager: Option<String>,
+
+    /// System name to evaluate in the flake instead of local hostname.
+    /// Explicit '--flake /etc/nixos#name' attribute takes precedence.
+    #[arg(long)]
+    pub(crate) host: Option<String>,
+
+    /// Print per-task and total run time as a JSON object to stderr.
+    #[arg(long)]
+    pub(crate) timing_json: bool,
+
+    /// Only report packages with at least this large version gap.
+    /// Packages with unknown gap are treated as 'patch'.
+    #[arg(long, value_enum, default_value_t = Severity::Patch)]
+    pub(crate) min_severity: Severity,
+
+    /// Only report packages with attributes starting with the prefix,
+    /// like 'python3Packages.'. Can be passed multiple times.
+    #[arg(long)]
+    pub(crate) attr_prefix: Vec<String>,
+
+    /// Regular expression of repology names to exclude from the report.
+    /// Can be passed multiple times.
+    #[arg(long)]
+    pub(crate) exclude: Vec<String>,
+
+    /// Minimum interval between repology page fetches in milliseconds.
+    /// 0 disables throttling (useful for private mirrors).
+    #[arg(long, default_value_t = 1000)]
+    pub(crate) repology_interval_ms: u64,
+
+    /// Base URL of repology projects API. Useful for private mirrors.
+    #[arg(
+        long,
+        default_value = "https://repology.org/api/v1/projects/",
+        value_parser = parse_http_url
+    )]
+    pub(crate) repology_url: String,
+
+    /// Read repology data from a local dump of API responses instead
+    /// of fetching it over network.
+    #[arg(long)]
+    pub(crate) repology_dump: Option<String>,
+
+    /// Kill external commands ('nix', 'curl') running longer than this
+    /// many seconds. Unlimited by default.
+    #[arg(long)]
+    pub(crate) command_timeout: Option<u64>,
+
+    /// Also print a human-readable table of outdated packages to stderr
+    /// sorted by version gap.
+    #[arg(long)]
+    pub(crate) summary: bool,
+
+    /// Colorize human-readable summary.
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto)]
+    pub(crate) color: ColorChoice,
+
+    /// Also include packages installed with 'nix profile install'.
+    /// Defaults to ~/.nix-profile when passed without a value.
+    #[arg(long, num_args = 0..=1, default_missing_value = "~/.nix-profile")]
+    pub(crate) profile: Option<String>,
+
+    /// Pick repology repository ('nix_stable_XX_YY' or 'nix_unstable')
+    /// matching the channel of system's nixpkgs.
+    #[arg(long)]
+    pub(crate) stable_channel: bool,
+
+    /// Exit with code 2 when outdated packages exceed this percentage
+    /// of installed packages.
+    #[arg(long, value_parser = parse_percent)]
+    pub(crate) fail_threshold: Option<f64>,
+
+    /// Print JSON Schema of outdated package records and exit.
+    #[arg(long)]
+    pub(crate) print_schema: bool,
+
+    /// Print external commands ('nix', 'curl') instead of running them.
+    #[arg(long)]
+    pub(crate) dry_run: bool,
+
+    /// Only consider repology packages with these statuses, like
+    /// 'outdated,legacy,devel'. All statuses are considered by default.
+    #[arg(long, value_delimiter = ',')]
+    pub(crate) include_status: Vec<String>,
+
+    /// Append a JSON line with run summary (hostname, timestamp,
+    /// package counts) to this file.
+    #[arg(long)]
+    pub(crate) audit_log: Option<String>,
+
+    /// Compare against packages of another nixpkgs (a path or a flake
+    /// reference) instead of repology.
+    #[arg(long)]
+    pub(crate) compare_nixpkgs: Option<String>,
+
+    /// Order of outdated packages. Ties are ordered by repology name.
+    #[arg(long, value_enum, default_value_t = SortKey::Name)]
+    pub(crate) sort: SortKey,
+
+    /// Also report packages with installed version newer than the latest
+    /// one known to repology.
+    #[arg(long)]
+    pub(crate) show_ahead: bool,
+
+    /// List all outdated nixpkgs packages according to repology without
+    /// evaluating local system.
+    #[arg(long)]
+    pub(crate) repology_only: bool,
+
+    /// Experimental: fetch this many alphabetic ranges of repology
+    /// projects in parallel. '--repology-interval-ms' still applies to
+    /// all fetches combined.
+    #[arg(long, default_value = "1")]
+    pub(crate) repology_concurrency: NonZeroUsize,
+
+    /// Only inspect this repology project. Can be passed multiple
+    /// times. Fetches just these projects from repology.
+    #[arg(long, value_parser = NonEmptyStringValueParser::new())]
+    pub(crate) only: Vec<String>,
+
+    /// Report partial results if some of data sources (repology,
+    /// installed or available packages) fail.
+    #[arg(long)]
+    pub(crate) best_effort: bool,
+
+    /// Fetch repology data via this proxy, like 'http://proxy:3128'.
+    /// '$http_proxy' and '$https_proxy' are used by default.
+    #[arg(long, value_parser = parse_proxy_url)]
+    pub(crate) proxy: Option<String>,
+
+    /// Append this identity (like contact email) to the user agent of
+    /// repology requests so repology admins could reach out.
+    #[arg(long, value_parser = parse_header_value)]
+    pub(crate) user_agent_suffix: Option<String>,
+
+    /// Print installed nixpkgs attributes of given repology project
+    /// one per line and exit. Handy for 'nix edit'.
+    #[arg(
+        long,
+        value_name = "REPOLOGY_NAME",
+        value_parser = NonEmptyStringValueParser::new(),
+        conflicts_with_all = ["repology_only", "only"]
+    )]
+    pub(crate) list_attributes: Option<String>,
+
+    /// 'curl' binary to fetch repology data with.
+    #[arg(long, default_value = "curl", value_parser = NonEmptyStringValueParser::new())]
+    pub(crate) curl_path: String,
+
+    /// Extra 'curl' argument, like '--curl-arg=--cacert' and
+    /// '--curl-arg=/path/to/ca.pem'. Can be passed multiple times.
+    #[arg(long, allow_hyphen_values = true)]
+    pub(crate) curl_arg: Vec<String>,
+
+    /// Read default options from this TOML file. Keys are long option
+    /// names. Defaults to '~/.config/nix-olde/config.toml' if it exists.
+    #[arg(long)]
+    pub(crate) config: Option<String>,
+
+    /// Ignore the default config file. Handy to turn off options it
+    /// enables.
+    #[arg(long, conflicts_with = "config")]
+    pub(crate) no_config: bool,
+
+    /// Print available packages missing on repology. These are often
+    /// renames or new packages. Fetches all repology projects, not just
+    /// outdated ones.
+    #[arg(long)]
+    pub(crate) show_missing_repology: bool,
+
+    /// Evaluate packages for this platform, like 'aarch64-linux'.
+    /// Useful for systems deployed with 'nixos-rebuild --target-host'.
+    #[arg(long, value_parser = parse_system)]
+    pub(crate) system: Option<String>,
+
+    /// Report only this many first outdated packages after '--sort'.
+    /// Summary lines still count all of them.
+    #[arg(long, conflicts_with = "show_all")]
+    pub(crate) limit: Option<usize>,
+
+    /// Skip the slow 'nix-env -qa' query of available packages and
+    /// match installed packages against repology by their names.
+    /// Attributes are replaced with package names.
+    #[arg(long, conflicts_with_all = ["attr_prefix", "list_attributes", "repology_only"])]
+    pub(crate) no_available: bool,
+
+    /// Rendering of a fatal error on stderr.
+    #[arg(long, value_enum, default_value_t = ErrorFormat::Text)]
+    pub(crate) error_format: ErrorFormat,
+
+    /// Read installed packages from a file with 'nix show-derivation -r'
+    /// or 'nix path-info -r --json' output captured elsewhere instead
+    /// of evaluating local system.
+    #[arg(long, conflicts_with_all = ["home_manager", "profile", "repology_only"])]
+    pub(crate) closure_json: Option<String>,
+
+    /// Compare two saved 'ndjson' or 'json-array' reports and print
+    /// newly outdated, fixed and changed packages. Runs no 'nix' or
+    /// network queries.
+    #[arg(
+        long,
+        num_args = 2,
+        value_names = ["OLD", "NEW"],
+        conflicts_with_all = ["repology_only", "list_attributes", "print_schema"]
+    )]
+    pub(crate) diff: Option<Vec<String>>,
+
+    /// Read installed packages from an existing system generation
+    /// '/nix/var/nix/profiles/system-<N>-link' instead of evaluating
+    /// system configuration.
+    #[arg(
+        long,
+        conflicts_with_all = ["closure_json", "home_manager", "profile", "repology_only"]
+    )]
+    pub(crate) generation: Option<u32>,
+
+    /// Print a completion script for the shell to stdout and exit.
+    #[arg(long, hide = true, value_enum)]
+    pub(crate) generate_completions: Option<clap_complete::Shell>,
+
+    /// Only fetch repology data into '--cache-dir' and exit. Useful to
+    /// warm a shared cache separately from per-host runs.
+    #[arg(
+        long,
+        requires = "cache_dir",
+        conflicts_with_all = ["compare_nixpkgs", "repology_dump", "list_attributes", "diff"]
+    )]
+    pub(crate) fetch_only: bool,
+
+    /// Rendering of log messages on stderr. Progress and summary lines
+    /// are not affected.
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    pub(crate) log_format: LogFormat,
+
+    /// Also report up to date packages with 'up_to_date' field set in
+    /// JSON formats. Fetches all repology projects instead of outdated
+    /// ones.
+    #[arg(long, conflicts_with_all = ["repology_only", "diff"])]
+    pub(crate) show_all: bool,
+
+    /// Omit 'repology_url' field with repology project web page from
+    /// JSON records.
+    #[arg(long)]
+    pub(crate) no_repology_url: bool,
+
+    /// Pretty-print each 'ndjson' record across multiple lines.
+    /// Records are separated by a blank line.
+    #[arg(long)]
+    pub(crate) pretty: bool,
+
+    /// Wrap 'json-array' output into an object with 'nixpkgs_revision'
+    /// and 'outdated' package records.
+    #[arg(long)]
+    pub(crate) json_envelope: bool,
+
+    /// Add 'closure_size_bytes' field with closure size of installed
+    /// store paths to JSON records. Queries 'nix path-info -S'.
+    #[arg(long, conflicts_with_all = ["repology_only", "diff"])]
+    pub(crate) with_size: bool,
+
+    /// Pass '--arg <NAME> <VALUE>' to 'nix-env -qa' query of available
+    /// packages, like 'config "{ allowUnfree = true; }"'. Can be passed
+    /// multiple times.
+    #[arg(long, num_args = 2, value_names = ["NAME", "VALUE"], allow_hyphen_values = true)]
+    pub(crate) nix_arg: Vec<String>,
+
+    /// Pass '--argstr <NAME> <VALUE>' to 'nix-env -qa' query of
+    /// available packages. Can be passed multiple times.
+    #[arg(long, num_args = 2, value_names = ["NAME", "VALUE"], allow_hyphen_values = true)]
+    pub(crate) nix_argstr: Vec<String>,
+
+    /// Re-run every INTERVAL ('90s', '15m', '1h') and print a fresh
+    /// report. Failed runs are reported and retried on the next run.
+    #[arg(
+        long,
+        value_parser = parse_interval,
+        requires = "cache_dir",
+        conflicts_with_all = ["diff", "list_attributes", "print_schema", "dry_run"]
+    )]
+    pub(crate) watch: Option<Duration>,
+
+    /// 'config' expression of 'nix-env -qa' query of available
+    /// packages instead of
+    /// 'import <nixpkgs/pkgs/top-level/packages-config.nix>'.
+    #[arg(long, value_name = "EXPR", value_parser = NonEmptyStringValueParser::new())]
+    pub(crate) packages_config: Option<String>,
+
+    /// Add 'maintainers' field with repology maintainers of nixpkgs
+    /// packages to JSON records. Fetches each outdated project from
+    /// repology once more.
+    #[arg(long, conflicts_with_all = ["repology_only", "compare_nixpkgs", "diff"])]
+    pub(crate) with_maintainers: bool,
+
+    /// Only match installed packages to repology projects with the same
+    /// major version or a similar name. Rejected matches are logged at
+    /// debug level.
+    #[arg(long)]
+    pub(crate) strict_pname_match: bool,
+
+    /// Write installed packages as a JSON array to a file before
+    /// matching them against repology. A debugging aid.
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["repology_only", "diff"])]
+    pub(crate) dump_installed: Option<String>,
+
+    /// Write available packages as a JSON array to a file before
+    /// matching them against repology. A debugging aid.
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["repology_only", "diff"])]
+    pub(crate) dump_available: Option<String>,
+
+    /// Compare against pre-release latest versions like '2.0-rc1'.
+    /// By default the most recent stable version is used instead.
+    #[arg(long, conflicts_with_all = ["compare_nixpkgs", "diff"])]
+    pub(crate) include_pre_release: bool,
+}
+
+impl Opts {
+    /// Logging level: 'info' lowered by '--silent' and raised by
+    /// '--verbose'.
+    pub(crate) fn log_level_filter(&self) -> log::LevelFilter {
+        Verbosity::<InfoLevel>::new(self.verbose, self.silent).log_level_filter()
+    }
 }
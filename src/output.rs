@@ -0,0 +1,803 @@
+use std::collections::BTreeSet;
+use std::io::IsTerminal;
+use std::io::Write;
+
+use nix_olde::*;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::opts::*;
+
+fn repology_version(p: &OutdatedPackage) -> &str {
+    p.repology_version.as_deref().unwrap_or("<none>")
+}
+
+/// Sorts outdated packages by 'key' and then by repology name.
+pub(crate) fn sort_outdated(ps: &mut [OutdatedPackage], key: SortKey) {
+    match key {
+        SortKey::Name => ps.sort_by(|a, b| a.repology_name.cmp(&b.repology_name)),
+        SortKey::Severity => ps.sort_by(|a, b| {
+            b.severity
+                .cmp(&a.severity)
+                .then_with(|| a.repology_name.cmp(&b.repology_name))
+        }),
+        SortKey::Attribute => ps.sort_by(|a, b| {
+            a.attribute
+                .first()
+                .cmp(&b.attribute.first())
+                .then_with(|| a.repology_name.cmp(&b.repology_name))
+        }),
+    }
+}
+
+/// Writes a line per package labeled with the source of the latest
+/// version: 'repology' or 'reference'. Packages that are not outdated
+/// are marked with their state, like '(current)'.
+fn write_plain(w: &mut dyn Write, ps: &[OutdatedPackage]) -> Result<(), OldeError> {
+    for p in ps {
+        let mark = match p.state {
+            State::Outdated => String::new(),
+            s => format!(" ({})", s.as_str()),
+        };
+        writeln!(
+            w,
+            "{} {} {:?} | nixpkgs {:?} {:?}{mark}",
+            p.source.as_str(),
+            p.repology_name,
+            repology_version(p),
+            p.nixpkgs_version,
+            p.attribute
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes a compact JSON object per line. With 'pretty' each object
+/// spans multiple lines and objects are separated by a blank line.
+pub(crate) fn write_ndjson<T: Serialize>(
+    w: &mut dyn Write,
+    ps: &[T],
+    pretty: bool,
+) -> Result<(), OldeError> {
+    for (i, p) in ps.iter().enumerate() {
+        if !pretty {
+            writeln!(w, "{}", serde_json::to_string(p)?)?;
+            continue;
+        }
+        if i > 0 {
+            writeln!(w)?;
+        }
+        writeln!(w, "{}", serde_json::to_string_pretty(p)?)?;
+    }
+    Ok(())
+}
+
+/// Writes a JSON array of packages. With 'envelope' the array is
+/// wrapped into an object along with 'nixpkgs_revision'.
+fn write_json_array(
+    w: &mut dyn Write,
+    ps: &[OutdatedPackage],
+    nixpkgs_revision: Option<&str>,
+    envelope: bool,
+) -> Result<(), OldeError> {
+    match envelope {
+        false => serde_json::to_writer_pretty(&mut *w, ps)?,
+        true => serde_json::to_writer_pretty(
+            &mut *w,
+            &serde_json::json!({
+                "nixpkgs_revision": nixpkgs_revision,
+                "outdated": ps,
+            }),
+        )?,
+    }
+    writeln!(w)?;
+    Ok(())
+}
+
+/// Returns JSON Schema of an outdated package record emitted by 'JSON'
+/// formats.
+pub(crate) fn outdated_schema() -> Value {
+    let str_set = |description: &str| {
+        serde_json::json!({
+            "description": description,
+            "type": "array",
+            "items": { "type": "string" },
+            "uniqueItems": true,
+        })
+    };
+    serde_json::json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "nix-olde outdated package",
+        "type": "object",
+        "properties": {
+            "repology_name": {
+                "description": "Repology project name.",
+                "type": "string",
+            },
+            "attribute": str_set("nixpkgs attributes of the package."),
+            "repology_version": {
+                "description": "Latest version known to repology.",
+                "type": ["string", "null"],
+            },
+            "nixpkgs_version": str_set("Installed versions."),
+            "installed": {
+                "description": "Installed versions paired with their attributes.",
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "attribute": { "type": "string" },
+                        "version": { "type": "string" },
+                    },
+                    "required": ["attribute", "version"],
+                    "additionalProperties": false,
+                },
+            },
+            "severity": {
+                "description": "Gap between the most recent installed version and the latest one.",
+                "enum": ["unknown", "patch", "minor", "major"],
+            },
+            "status": str_set("Repology statuses of nixpkgs packages."),
+            "state": {
+                "description": "Whether installed version is behind, ahead of or the same as the latest one.",
+                "enum": ["outdated", "ahead", "current"],
+            },
+            "source": {
+                "description": "Source of 'repology_version': repology or the reference nixpkgs of '--compare-nixpkgs'.",
+                "enum": ["repology", "reference"],
+            },
+            "repology_url": {
+                "description": "Repology web page of the project. Absent with '--no-repology-url' or '--compare-nixpkgs'.",
+                "type": "string",
+            },
+            "closure_size_bytes": {
+                "description": "Largest closure size in bytes among installed store paths. Only present with '--with-size' for built packages.",
+                "type": "integer",
+                "minimum": 0,
+            },
+            "maintainers": str_set("Repology maintainers of nixpkgs packages. Only present with '--with-maintainers'."),
+            "up_to_date": {
+                "description": "Whether installed version is the latest one. Only present with '--show-all'.",
+                "type": "boolean",
+            },
+        },
+        "required": [
+            "repology_name",
+            "attribute",
+            "repology_version",
+            "nixpkgs_version",
+            "installed",
+            "severity",
+            "status",
+            "state",
+        ],
+        "additionalProperties": false,
+    })
+}
+
+/// Package counts and run details complementing outdated packages
+/// list.
+pub(crate) struct Totals<'a> {
+    /// Installed packages matching '--attr-prefix'.
+    pub(crate) installed: usize,
+    /// Installed packages not found in available packages.
+    pub(crate) missing_available: usize,
+    /// Git revision of checked nixpkgs if known.
+    pub(crate) nixpkgs_revision: Option<&'a str>,
+}
+
+/// Writes a gauge in Prometheus text exposition format.
+fn write_gauge(
+    w: &mut dyn Write,
+    name: &str,
+    help: &str,
+    samples: &[(&str, usize)],
+) -> std::io::Result<()> {
+    writeln!(w, "# HELP {name} {help}")?;
+    writeln!(w, "# TYPE {name} gauge")?;
+    for (labels, v) in samples {
+        writeln!(w, "{name}{labels} {v}")?;
+    }
+    Ok(())
+}
+
+fn write_prometheus(
+    w: &mut dyn Write,
+    ps: &[OutdatedPackage],
+    totals: &Totals,
+) -> Result<(), OldeError> {
+    write_gauge(
+        w,
+        "nix_olde_outdated_total",
+        "Number of outdated installed packages.",
+        &[("", ps.iter().filter(|p| p.is_outdated()).count())],
+    )?;
+    write_gauge(
+        w,
+        "nix_olde_installed_total",
+        "Number of installed packages.",
+        &[("", totals.installed)],
+    )?;
+    write_gauge(
+        w,
+        "nix_olde_missing_available_total",
+        "Number of installed packages missing in available packages.",
+        &[("", totals.missing_available)],
+    )?;
+    if let Some(rev) = totals.nixpkgs_revision {
+        // Label values escape backslashes, quotes and newlines.
+        let rev = rev
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n");
+        write_gauge(
+            w,
+            "nix_olde_nixpkgs_info",
+            "Git revision of checked nixpkgs.",
+            &[(&format!("{{revision=\"{rev}\"}}"), 1)],
+        )?;
+    }
+
+    let severities = [
+        Severity::Unknown,
+        Severity::Patch,
+        Severity::Minor,
+        Severity::Major,
+    ];
+    let labels: Vec<String> = severities
+        .iter()
+        .map(|s| format!("{{severity=\"{}\"}}", s.as_str()))
+        .collect();
+    let samples: Vec<(&str, usize)> = severities
+        .iter()
+        .zip(&labels)
+        .map(|(s, l)| {
+            let n = ps
+                .iter()
+                .filter(|p| p.is_outdated() && p.severity == *s)
+                .count();
+            (l.as_str(), n)
+        })
+        .collect();
+    write_gauge(
+        w,
+        "nix_olde_outdated_by_severity",
+        "Number of outdated installed packages by version gap severity.",
+        &samples,
+    )?;
+    Ok(())
+}
+
+/// ANSI escape sequences for coloured output.
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+const RESET: &str = "\x1b[0m";
+
+/// Returns cell style of a (row, column) position.
+type CellStyle<'a> = &'a dyn Fn(usize, usize) -> Option<&'static str>;
+
+/// Resolves '--color' choice for stderr output. 'auto' enables colors
+/// on a terminal unless NO_COLOR is set.
+pub(crate) fn use_color(choice: ColorChoice) -> bool {
+    match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            std::io::stderr().is_terminal()
+                && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+        }
+    }
+}
+
+/// Writes 'rows' as left-aligned columns under 'header'. 'style'
+/// colours individual data cells.
+fn write_columns(
+    w: &mut dyn Write,
+    header: &[&str],
+    rows: &[Vec<String>],
+    style: CellStyle,
+) -> Result<(), OldeError> {
+    let mut widths: Vec<usize> = header.iter().map(|h| h.chars().count()).collect();
+    for r in rows {
+        for (w, c) in widths.iter_mut().zip(r) {
+            *w = (*w).max(c.chars().count());
+        }
+    }
+
+    let mut write_row = |cells: Vec<&str>, row: Option<usize>| -> std::io::Result<()> {
+        let line: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .enumerate()
+            .map(|(col, (c, cw))| match row.and_then(|r| style(r, col)) {
+                None => format!("{c:cw$}"),
+                // Padding is kept outside of escape sequences.
+                Some(s) => format!("{s}{c}{RESET}{:pad$}", "", pad = cw - c.chars().count()),
+            })
+            .collect();
+        writeln!(w, "{}", line.join("  ").trim_end())
+    };
+    write_row(header.to_vec(), None)?;
+    for (i, r) in rows.iter().enumerate() {
+        write_row(r.iter().map(|c| c.as_str()).collect(), Some(i))?;
+    }
+    Ok(())
+}
+
+/// Joins a set of strings with commas.
+fn join_set(s: &BTreeSet<String>) -> String {
+    s.iter().map(|e| e.as_str()).collect::<Vec<_>>().join(", ")
+}
+
+fn write_table(w: &mut dyn Write, ps: &[OutdatedPackage]) -> Result<(), OldeError> {
+    let header = [
+        "repology_name",
+        "attribute",
+        "current",
+        "latest",
+        "severity",
+        "state",
+    ];
+    let rows: Vec<Vec<String>> = ps
+        .iter()
+        .map(|p| {
+            vec![
+                p.repology_name.clone(),
+                join_set(&p.attribute),
+                join_set(&p.nixpkgs_version),
+                repology_version(p).to_string(),
+                p.severity.as_str().to_string(),
+                p.state.as_str().to_string(),
+            ]
+        })
+        .collect();
+    write_columns(w, &header, &rows, &|_, _| None)
+}
+
+/// Quotes CSV field if it contains separators, quotes or newlines.
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn write_csv_row(w: &mut dyn Write, row: &[&str]) -> Result<(), OldeError> {
+    let fields: Vec<String> = row.iter().map(|f| csv_field(f)).collect();
+    writeln!(w, "{}", fields.join(","))?;
+    Ok(())
+}
+
+/// Writes a CSV row per package. Multi-valued fields are joined with
+/// ';'.
+fn write_csv(w: &mut dyn Write, ps: &[OutdatedPackage]) -> Result<(), OldeError> {
+    let join = |s: &BTreeSet<String>| s.iter().cloned().collect::<Vec<_>>().join(";");
+    write_csv_row(
+        w,
+        &[
+            "repology_name",
+            "attributes",
+            "repology_version",
+            "nixpkgs_versions",
+            "severity",
+            "state",
+        ],
+    )?;
+    for p in ps {
+        write_csv_row(
+            w,
+            &[
+                &p.repology_name,
+                &join(&p.attribute),
+                p.repology_version.as_deref().unwrap_or_default(),
+                &join(&p.nixpkgs_version),
+                p.severity.as_str(),
+                p.state.as_str(),
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+/// Escapes Markdown table cell: pipes would start a new cell.
+fn markdown_field(s: &str) -> String {
+    s.replace('|', "\\|").replace(['\n', '\r'], " ")
+}
+
+fn write_markdown_row(w: &mut dyn Write, row: &[&str]) -> Result<(), OldeError> {
+    let fields: Vec<String> = row.iter().map(|f| markdown_field(f)).collect();
+    writeln!(w, "| {} |", fields.join(" | "))?;
+    Ok(())
+}
+
+/// Writes GitHub-flavored Markdown table header.
+fn write_markdown_header(w: &mut dyn Write, header: &[&str]) -> Result<(), OldeError> {
+    write_markdown_row(w, header)?;
+    writeln!(w, "|{}", " --- |".repeat(header.len()))?;
+    Ok(())
+}
+
+/// Writes a GitHub-flavored Markdown table row per package.
+fn write_markdown(w: &mut dyn Write, ps: &[OutdatedPackage]) -> Result<(), OldeError> {
+    write_markdown_header(
+        w,
+        &[
+            "package",
+            "attribute",
+            "current",
+            "latest",
+            "state",
+            "repology",
+        ],
+    )?;
+    for p in ps {
+        let link = match &p.repology_url {
+            Some(u) => format!("[link]({u})"),
+            None => String::new(),
+        };
+        write_markdown_row(
+            w,
+            &[
+                &p.repology_name,
+                &join_set(&p.attribute),
+                &join_set(&p.nixpkgs_version),
+                repology_version(p),
+                p.state.as_str(),
+                &link,
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+/// Escapes GitHub Actions workflow command message.
+fn github_escape(s: &str) -> String {
+    s.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+/// Writes a GitHub Actions warning annotation per package.
+fn write_github(w: &mut dyn Write, ps: &[OutdatedPackage]) -> Result<(), OldeError> {
+    for p in ps.iter().filter(|p| p.is_outdated()) {
+        let message = format!(
+            "{} {} -> {}",
+            p.repology_name,
+            join_set(&p.nixpkgs_version),
+            repology_version(p)
+        );
+        writeln!(w, "::warning title=outdated::{}", github_escape(&message))?;
+    }
+    Ok(())
+}
+
+/// SARIF rule id of all reported packages.
+const SARIF_RULE_ID: &str = "outdated-package";
+
+/// Returns SARIF result of 'project' with a logical (non-file)
+/// location. 'level' is one of "error", "warning" or "note".
+fn sarif_result(project: &str, message: &str, level: &str, properties: Value) -> Value {
+    serde_json::json!({
+        "ruleId": SARIF_RULE_ID,
+        "level": level,
+        "message": { "text": message },
+        "locations": [{
+            "logicalLocations": [{ "name": project, "kind": "package" }],
+        }],
+        "properties": properties,
+    })
+}
+
+/// Writes SARIF 2.1.0 log of a single run with given 'results'. Known
+/// 'nixpkgs_revision' is added to run properties.
+fn write_sarif_log(
+    w: &mut dyn Write,
+    results: Vec<Value>,
+    nixpkgs_revision: Option<&str>,
+) -> Result<(), OldeError> {
+    let mut log = serde_json::json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "informationUri": "https://github.com/trofi/nix-olde",
+                    "rules": [{
+                        "id": SARIF_RULE_ID,
+                        "shortDescription": {
+                            "text": "Installed package is older than the latest version known to repology.",
+                        },
+                    }],
+                },
+            },
+            "results": results,
+        }],
+    });
+    if let Some(rev) = nixpkgs_revision {
+        log["runs"][0]["properties"] = serde_json::json!({ "nixpkgs_revision": rev });
+    }
+    serde_json::to_writer_pretty(&mut *w, &log)?;
+    writeln!(w)?;
+    Ok(())
+}
+
+/// Writes a SARIF result per package. Major updates are errors, minor
+/// ones are warnings and the rest are notes.
+fn write_sarif(
+    w: &mut dyn Write,
+    ps: &[OutdatedPackage],
+    nixpkgs_revision: Option<&str>,
+) -> Result<(), OldeError> {
+    let results = ps
+        .iter()
+        .filter(|p| p.is_outdated())
+        .map(|p| {
+            let message = format!(
+                "{} {} -> {} ({})",
+                p.repology_name,
+                join_set(&p.nixpkgs_version),
+                repology_version(p),
+                p.severity.as_str()
+            );
+            let level = match p.severity {
+                Severity::Major => "error",
+                Severity::Minor => "warning",
+                _ => "note",
+            };
+            let properties = serde_json::json!({
+                "attribute": p.attribute,
+                "severity": p.severity,
+                "repology_url": p.repology_url,
+            });
+            sarif_result(&p.repology_name, &message, level, properties)
+        })
+        .collect();
+    write_sarif_log(w, results, nixpkgs_revision)
+}
+
+/// Writes human-readable table of outdated packages sorted by version
+/// gap severity (largest first) and then by repology name. With
+/// 'color' major updates are red and minor updates are yellow.
+pub(crate) fn write_summary(
+    w: &mut dyn Write,
+    ps: &[OutdatedPackage],
+    color: bool,
+) -> Result<(), OldeError> {
+    let mut sorted: Vec<&OutdatedPackage> = ps.iter().filter(|p| p.is_outdated()).collect();
+    sorted.sort_by_key(|p| (std::cmp::Reverse(p.severity), p.repology_name.as_str()));
+
+    let header = ["repology_name", "attribute", "update", "severity"];
+    let rows: Vec<Vec<String>> = sorted
+        .iter()
+        .map(|p| {
+            vec![
+                p.repology_name.clone(),
+                join_set(&p.attribute),
+                format!(
+                    "{} -> {}",
+                    join_set(&p.nixpkgs_version),
+                    repology_version(p)
+                ),
+                p.severity.as_str().to_string(),
+            ]
+        })
+        .collect();
+    // Colour 'update' and 'severity' columns.
+    let style = |row: usize, col: usize| -> Option<&'static str> {
+        if !color || col < 2 {
+            return None;
+        }
+        match sorted[row].severity {
+            Severity::Major => Some(RED),
+            Severity::Minor => Some(YELLOW),
+            _ => None,
+        }
+    };
+    write_columns(w, &header, &rows, &style)
+}
+
+/// Renders outdated packages records in requested format. 'envelope'
+/// only affects 'json-array' format.
+pub(crate) fn write_outdated(
+    w: &mut dyn Write,
+    format: OutputFormat,
+    ps: &[OutdatedPackage],
+    totals: &Totals,
+    pretty: bool,
+    envelope: bool,
+) -> Result<(), OldeError> {
+    match format {
+        OutputFormat::Plain => write_plain(w, ps),
+        OutputFormat::Ndjson => write_ndjson(w, ps, pretty),
+        OutputFormat::JsonArray => write_json_array(w, ps, totals.nixpkgs_revision, envelope),
+        OutputFormat::Table => write_table(w, ps),
+        OutputFormat::Prometheus => write_prometheus(w, ps, totals),
+        OutputFormat::Csv => write_csv(w, ps),
+        OutputFormat::Github => write_github(w, ps),
+        OutputFormat::Markdown => write_markdown(w, ps),
+        OutputFormat::Sarif => write_sarif(w, ps, totals.nixpkgs_revision),
+    }
+}
+
+/// Appends a single JSON line summarizing the run to 'path'. The line
+/// is written with a single 'write_all()' to an 'O_APPEND' file to keep
+/// records of concurrent runs intact.
+pub(crate) fn append_audit_record(
+    path: &str,
+    installed: usize,
+    outdated: usize,
+    nixpkgs_revision: Option<&str>,
+) -> Result<(), OldeError> {
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let record = serde_json::json!({
+        "hostname": gethostname::gethostname().to_string_lossy(),
+        "timestamp": timestamp,
+        "installed": installed,
+        "outdated": outdated,
+        "nixpkgs_revision": nixpkgs_revision,
+        "version": env!("CARGO_PKG_VERSION"),
+    });
+    let mut f = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    f.write_all(format!("{record}\n").as_bytes())?;
+    Ok(())
+}
+
+/// Renders repology packages of '--repology-only' in requested format.
+pub(crate) fn write_repology(
+    w: &mut dyn Write,
+    format: OutputFormat,
+    ps: &[RepologyPackage],
+    pretty: bool,
+) -> Result<(), OldeError> {
+    let or_none = |v: &Option<String>| v.clone().unwrap_or_else(|| "<none>".to_string());
+    match format {
+        OutputFormat::Plain => {
+            for p in ps {
+                writeln!(
+                    w,
+                    "repology {} {:?} | nixpkgs {:?} {:?}",
+                    p.repology_name,
+                    or_none(&p.latest),
+                    or_none(&p.version),
+                    p.name
+                )?;
+            }
+        }
+        OutputFormat::Ndjson => write_ndjson(w, ps, pretty)?,
+        OutputFormat::JsonArray => {
+            serde_json::to_writer_pretty(&mut *w, ps)?;
+            writeln!(w)?;
+        }
+        OutputFormat::Table => {
+            let header = ["repology_name", "name", "current", "latest"];
+            let rows: Vec<Vec<String>> = ps
+                .iter()
+                .map(|p| {
+                    vec![
+                        p.repology_name.clone(),
+                        p.name.clone(),
+                        or_none(&p.version),
+                        or_none(&p.latest),
+                    ]
+                })
+                .collect();
+            write_columns(w, &header, &rows, &|_, _| None)?;
+        }
+        OutputFormat::Prometheus => {
+            write_gauge(
+                w,
+                "nix_olde_repology_outdated_total",
+                "Number of outdated nixpkgs packages according to repology.",
+                &[("", ps.len())],
+            )?;
+        }
+        OutputFormat::Csv => {
+            write_csv_row(w, &["repology_name", "name", "version", "latest"])?;
+            for p in ps {
+                write_csv_row(
+                    w,
+                    &[
+                        &p.repology_name,
+                        &p.name,
+                        p.version.as_deref().unwrap_or_default(),
+                        p.latest.as_deref().unwrap_or_default(),
+                    ],
+                )?;
+            }
+        }
+        OutputFormat::Github => {
+            for p in ps {
+                let message = format!(
+                    "{} {} -> {}",
+                    p.repology_name,
+                    or_none(&p.version),
+                    or_none(&p.latest)
+                );
+                writeln!(w, "::warning title=outdated::{}", github_escape(&message))?;
+            }
+        }
+        OutputFormat::Markdown => {
+            write_markdown_header(w, &["package", "name", "current", "latest"])?;
+            for p in ps {
+                write_markdown_row(
+                    w,
+                    &[
+                        &p.repology_name,
+                        &p.name,
+                        &or_none(&p.version),
+                        &or_none(&p.latest),
+                    ],
+                )?;
+            }
+        }
+        OutputFormat::Sarif => {
+            let results = ps
+                .iter()
+                .map(|p| {
+                    let message = format!(
+                        "{} {} -> {}",
+                        p.repology_name,
+                        or_none(&p.version),
+                        or_none(&p.latest)
+                    );
+                    let properties = serde_json::json!({ "name": p.name });
+                    sarif_result(&p.repology_name, &message, "warning", properties)
+                })
+                .collect();
+            write_sarif_log(w, results, None)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(format: OutputFormat, nixpkgs_revision: Option<&str>, envelope: bool) -> String {
+        let totals = Totals {
+            installed: 0,
+            missing_available: 0,
+            nixpkgs_revision,
+        };
+        let mut w = Vec::new();
+        write_outdated(&mut w, format, &[], &totals, false, envelope).unwrap();
+        String::from_utf8(w).unwrap()
+    }
+
+    #[test]
+    fn prometheus_reports_escaped_revision() {
+        let out = render(
+            OutputFormat::Prometheus,
+            Some("abc \"x\" (2025-01-14)"),
+            false,
+        );
+        assert!(out.contains("nix_olde_nixpkgs_info{revision=\"abc \\\"x\\\" (2025-01-14)\"} 1\n"));
+        let out = render(OutputFormat::Prometheus, None, false);
+        assert!(!out.contains("nix_olde_nixpkgs_info"));
+    }
+
+    #[test]
+    fn sarif_and_envelope_carry_revision() {
+        let sarif: Value =
+            serde_json::from_str(&render(OutputFormat::Sarif, Some("abc"), false)).unwrap();
+        assert_eq!(sarif["runs"][0]["properties"]["nixpkgs_revision"], "abc");
+
+        let json: Value =
+            serde_json::from_str(&render(OutputFormat::JsonArray, Some("abc"), true)).unwrap();
+        assert_eq!(json["nixpkgs_revision"], "abc");
+        assert_eq!(json["outdated"], serde_json::json!([]));
+        let json: Value =
+            serde_json::from_str(&render(OutputFormat::JsonArray, Some("abc"), false)).unwrap();
+        assert_eq!(json, serde_json::json!([]));
+    }
+}
@@ -1,32 +1,101 @@
-use std::time::Instant;
+use std::io::IsTerminal;
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Serializes progress output of concurrent tasks. Holds true if a
+/// live status line is currently displayed and needs to be cleared
+/// before any other output.
+static STATUS_LINE: Mutex<bool> = Mutex::new(false);
+
+const SPINNER: [char; 4] = ['|', '/', '-', '\\'];
+
+/// Prints a complete progress line. Clears live status line if any.
+fn print_line(line: &str) {
+    let mut shown = STATUS_LINE.lock().expect("progress lock");
+    if *shown {
+        eprint!("\r\x1b[K");
+        *shown = false;
+    }
+    eprintln!("{}", line);
+}
 
 pub(crate) struct TaskProgress<'a> {
     pub(crate) name: &'a str,
     pub(crate) failed: bool,
+    /// Set by '--quiet': all progress output is suppressed.
+    quiet: bool,
+    /// Extra detail to report along with the final status.
+    note: Option<String>,
     started: Instant,
+    /// Live status updates are only shown on a terminal.
+    live: bool,
+    /// Number of status updates so far. Drives the spinner.
+    updates: AtomicUsize,
 }
 
 impl<'a> TaskProgress<'a> {
-    pub(crate) fn new(name: &'a str) -> Self {
-        eprintln!("Fetching '{}'", name);
+    pub(crate) fn new(name: &'a str, quiet: bool) -> Self {
+        if !quiet {
+            print_line(&format!("Fetching '{}'", name));
+        }
         TaskProgress {
             name,
             failed: false,
+            quiet,
+            note: None,
             started: std::time::Instant::now(),
+            live: std::io::stderr().is_terminal() && !quiet,
+            updates: AtomicUsize::new(0),
         }
     }
     pub(crate) fn fail(&mut self) {
         self.failed = true;
     }
+    pub(crate) fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+    pub(crate) fn set_note(&mut self, note: &str) {
+        self.note = Some(note.to_string());
+    }
+    /// Shows intermediate task status like 'page 37' in a live status
+    /// line. Does nothing when stderr is not a terminal.
+    pub(crate) fn update(&self, msg: &str) {
+        if !self.live {
+            return;
+        }
+        let n = self.updates.fetch_add(1, Ordering::Relaxed);
+        let mut shown = STATUS_LINE.lock().expect("progress lock");
+        eprint!(
+            "\r\x1b[K{} {}: {} ({:.0} s)",
+            SPINNER[n % SPINNER.len()],
+            self.name,
+            msg,
+            self.started.elapsed().as_secs_f64()
+        );
+        let _ = std::io::stderr().flush();
+        *shown = true;
+    }
 }
 
 impl Drop for TaskProgress<'_> {
     fn drop(&mut self) {
+        if self.quiet {
+            return;
+        }
         let status = match self.failed {
             true => "failed",
             false => "done",
         };
+        let note = match &self.note {
+            None => String::new(),
+            Some(n) => format!(" ({n})"),
+        };
         let took = self.started.elapsed().as_secs_f64();
-        eprintln!("'{}' {}, took {:.2} s.", self.name, status, took);
+        print_line(&format!(
+            "'{}' {}{}, took {:.2} s.",
+            self.name, status, note, took
+        ));
     }
 }
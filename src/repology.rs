@@ -1,10 +1,19 @@
+use std::cmp::Ordering;
 use std::collections::BTreeMap;
 use std::collections::BTreeSet;
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 use serde_derive::Deserialize;
 
+use crate::available;
+use crate::cache::*;
 use crate::cmd::*;
 use crate::error::*;
+use crate::http;
+use crate::progress::*;
+use crate::version::*;
 
 /// Installed packages with available 'pname' and 'version' attributes.
 #[derive(Eq, PartialEq, Ord, PartialOrd)]
@@ -15,9 +24,9 @@ pub(crate) struct Package {
     /// nixpkgs 'pname' from available packages
     pub(crate) name: String,
 
-    version: Option<String>,
+    pub(crate) version: Option<String>,
     /// repology's characterization of the state: outdated, dev-only, etc.
-    status: Option<String>,
+    pub(crate) status: Option<String>,
 
     /// latest version available in some other repository
     /// Might not exist if latest version was added and then
@@ -25,104 +34,710 @@ pub(crate) struct Package {
     pub(crate) latest: Option<String>,
 }
 
-/// Returns list of all outdated derivations according to repology.
-pub(crate) fn get_packages(
-    cancel_fetch: &dyn Fn() -> bool,
-) -> Result<BTreeSet<Package>, OldeError> {
+/// Parameters of repology database fetch.
+pub(crate) struct FetchOptions {
+    /// Base URL of projects API with trailing slash.
+    pub(crate) url: String,
+    /// Repology repository name to compare against.
+    pub(crate) repo: String,
+    /// Number of retries of a failed page fetch.
+    pub(crate) retries: u32,
+    /// Local file with pre-fetched pages to use instead of network.
+    pub(crate) dump: Option<String>,
+    /// Optional on-disk cache of fetched pages.
+    pub(crate) cache: Option<Cache>,
+    /// Minimum delay between page fetches. Zero disables throttling.
+    /// Also an initial delay before retrying a failed fetch if it is
+    /// longer than 'MIN_RETRY_DELAY'.
+    pub(crate) min_fetch_interval: Duration,
+    /// Number of name space ranges fetched in parallel.
+    pub(crate) concurrency: usize,
+    /// Only fetch these projects. All outdated projects if empty.
+    pub(crate) only: Vec<String>,
+    /// Fetch all projects of 'repo', not just outdated ones.
+    pub(crate) all: bool,
+    /// Accept pre-release versions like '2.0-rc1' as the latest ones.
+    pub(crate) pre_release: bool,
+    /// HTTP client parameters.
+    pub(crate) http: http::HttpOptions,
+    /// Caller identity (like contact email) appended to user agent.
+    pub(crate) user_agent_suffix: Option<String>,
+}
+
+/// Fetches a single page starting at 'suffix' project.
+fn fetch_page(opts: &FetchOptions, suffix: &str, ctx: &CmdContext) -> Result<Vec<u8>, OldeError> {
+    let repo: &str = &opts.repo;
+    let filter = if opts.all { "" } else { "&outdated=1" };
+    let url = format!("{}{suffix}?inrepo={repo}{filter}", opts.url);
+    fetch_url(opts, &url, ctx)
+}
+
+/// Shortest initial delay before retrying a failed fetch. Retries are
+/// spaced out even without throttling of regular fetches.
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Returns delay before retry number 'attempt' (starting from 0):
+/// doubled on each attempt.
+fn retry_delay(min_fetch_interval: Duration, attempt: u32) -> Duration {
+    min_fetch_interval.max(MIN_RETRY_DELAY) * 2u32.pow(attempt)
+}
+
+/// Fetches 'url' retrying transient failures.
+fn fetch_url(opts: &FetchOptions, url: &str, ctx: &CmdContext) -> Result<Vec<u8>, OldeError> {
+    let mut user_agent = format!(
+        "{}/{} (+{})",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        "https://github.com/trofi/nix-olde"
+    );
+    if let Some(s) = &opts.user_agent_suffix {
+        user_agent.push(' ');
+        user_agent.push_str(s);
+    }
+
+    // Transient failures (like 429 throttling or 5xx errors) are
+    // retried with an increased delay for the same 'url'.
+    let mut attempt: u32 = 0;
+    loop {
+        log::debug!("Fetching from repology: {:?}", url);
+        match http::get(url, &user_agent, &opts.http, ctx) {
+            Ok(c) => return Ok(c),
+            Err(e) if attempt < opts.retries && http::is_transient(&e) => {
+                let delay = retry_delay(opts.min_fetch_interval, attempt);
+                attempt += 1;
+                log::info!(
+                    "Repology fetch failed: {e}. Retry {attempt}/{} in {delay:?}",
+                    opts.retries
+                );
+                std::thread::sleep(delay);
+                if ctx.is_canceled() {
+                    return Err(OldeError::Canceled(String::from("Repology fetch")));
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// {
+//   "python:networkx": [
+//     {
+//       "repo": "nix_unstable",
+//       "visiblenamename": "python3.10-networkx",
+//       "version": "2.8.6",
+//       "status": "outdated",
+//     },
+
+#[derive(Deserialize, Debug)]
+/// Dervivation description with subset of fields needed to detect outdated packages.
+struct Repology {
+    repo: String,
+    // Older API documentation spells it as 'visiblenamename'.
+    #[serde(alias = "visiblenamename")]
+    visiblename: Option<String>,
+    version: Option<String>,
+    status: Option<String>,
+    #[serde(default)]
+    maintainers: Vec<String>,
+}
+
+impl Repology {
+    /// Returns package name. Entries without 'visiblename' get it
+    /// from the 'project' name without repology namespace prefix:
+    /// "python:networkx" -> "networkx".
+    fn name(&self, project: &str) -> String {
+        match &self.visiblename {
+            Some(vn) => vn.clone(),
+            None => {
+                log::debug!("Entry without 'visiblename' in {project:?}: {self:?}");
+                project
+                    .split_once(':')
+                    .map_or(project, |(_, n)| n)
+                    .to_string()
+            }
+        }
+    }
+}
+
+/// A page of repology projects: project name to its packages.
+type Page = BTreeMap<String, Vec<Repology>>;
+
+/// Returns web page of 'project' versions on repology instance serving
+/// projects API at 'api_url'. Example:
+///     "https://repology.org/api/v1/projects/", "python:networkx" ->
+///     "https://repology.org/project/python:networkx/versions"
+pub(crate) fn project_url(api_url: &str, project: &str) -> String {
+    let base = api_url.strip_suffix("api/v1/projects/").unwrap_or(api_url);
+    format!("{base}project/{project}/versions")
+}
+
+/// Returns the latest version of a project out of its packages 'vs'.
+/// Pre-release latest versions are replaced with the most recent
+/// stable one unless 'opts.pre_release' is set or 'opts.repo' already
+/// has the pre-release.
+fn latest_version(project: &str, vs: &[Repology], opts: &FetchOptions) -> Option<String> {
+    let olatest_entry = vs
+        .iter()
+        .find(|e| e.status == Some("newest".to_string()) || e.status == Some("unique".to_string()));
+    let latest = olatest_entry.and_then(|oe| oe.version.as_deref())?;
+    let taken = vs
+        .iter()
+        .any(|e| e.repo == opts.repo && e.version.as_deref() == Some(latest));
+    if opts.pre_release || taken || !is_pre_release(latest) {
+        return Some(latest.to_string());
+    }
+
+    let stable = vs
+        .iter()
+        .filter_map(|e| e.version.as_deref())
+        .filter(|v| !is_pre_release(v));
+    match newest_numeric(stable) {
+        Some(s) => {
+            log::debug!("Using {s:?} instead of pre-release {latest:?} as latest {project}");
+            Some(s.to_string())
+        }
+        // Better than reporting a package without known version.
+        None => Some(latest.to_string()),
+    }
+}
+
+/// Adds 'opts.repo' packages from a page to 'r'.
+fn add_page_packages(r: &mut BTreeSet<Package>, pkgs: &Page, opts: &FetchOptions) {
+    let repo: &str = &opts.repo;
+    for (n, vs) in pkgs {
+        let latest = latest_version(n, vs, opts);
+
+        // There can be multiple 'repo' package entries for a
+        // single repology entry: pycropto vs pycryptodome.
+        // Store all of them.
+        for v in vs {
+            if v.repo != repo {
+                continue;
+            }
+
+            r.insert(Package {
+                repology_name: n.clone(),
+                name: v.name(n),
+                version: v.version.clone(),
+                status: v.status.clone(),
+                latest: latest.clone(),
+            });
+        }
+    }
+}
+
+/// Builds repology-like packages out of packages available in a
+/// reference nixpkgs: each 'pname' is a project with its newest
+/// version as the latest one.
+pub(crate) fn from_available(ps: &BTreeSet<available::Package>) -> BTreeSet<Package> {
+    let mut newest: BTreeMap<&str, &str> = BTreeMap::new();
+    for p in ps {
+        let v = newest.entry(&p.pname).or_insert(&p.version);
+        if compare_nix(v, &p.version) == Ordering::Less {
+            *v = &p.version;
+        }
+    }
+
+    newest
+        .into_iter()
+        .map(|(pname, v)| Package {
+            repology_name: pname.to_string(),
+            name: pname.to_string(),
+            version: Some(v.to_string()),
+            status: None,
+            latest: Some(v.to_string()),
+        })
+        .collect()
+}
+
+/// Reads packages from a local dump of repology API responses: either
+/// a single JSON map or concatenated pages.
+fn read_dump(path: &str, opts: &FetchOptions) -> Result<BTreeSet<Package>, OldeError> {
+    let mut r = BTreeSet::new();
+
+    let contents_u8 = std::fs::read(path)?;
+    for page in serde_json::Deserializer::from_slice(&contents_u8).into_iter::<Page>() {
+        add_page_packages(&mut r, &page?, opts);
+    }
+
+    Ok(r)
+}
+
+/// Spaces out network fetches of all concurrent shards by at least
+/// 'interval'.
+struct Throttle {
+    interval: Duration,
+    /// Earliest time of the next fetch.
+    next: Mutex<Option<Instant>>,
+}
+
+impl Throttle {
+    fn new(interval: Duration) -> Self {
+        Throttle {
+            interval,
+            next: Mutex::new(None),
+        }
+    }
+
+    /// Blocks until the next fetch is allowed. The lock is held while
+    /// sleeping to queue concurrent callers.
+    fn wait(&self) {
+        let mut next = self.next.lock().expect("throttle lock");
+        let now = Instant::now();
+        let at = match *next {
+            Some(n) if n > now => {
+                std::thread::sleep(n - now);
+                n
+            }
+            _ => now,
+        };
+        *next = Some(at + self.interval);
+    }
+}
+
+/// Splits project name space into 'n' ranges by their first letter.
+/// Returns start of each range. The first range starts at "".
+fn shard_starts(n: usize) -> Vec<String> {
+    const LETTERS: &[u8] = b"abcdefghijklmnopqrstuvwxyz";
+    let n = n.clamp(1, LETTERS.len());
+    let mut starts = vec![String::new()];
+    starts.extend((1..n).map(|i| (LETTERS[i * LETTERS.len() / n] as char).to_string()));
+    starts
+}
+
+/// Maximum number of pages in a single range. Full unfiltered
+/// repository takes a few thousand pages.
+const MAX_PAGES: usize = 10_000;
+
+/// Fetches pages of projects in ['start'; 'end') range. Returns
+/// packages, number of projects in the range and true if at least one
+/// page was fetched over network.
+fn fetch_range(
+    opts: &FetchOptions,
+    start: &str,
+    end: Option<&str>,
+    throttle: &Throttle,
+    pages: &AtomicUsize,
+    progress: &TaskProgress,
+    ctx: &CmdContext,
+) -> Result<(BTreeSet<Package>, usize, bool), OldeError> {
+    let repo: &str = &opts.repo;
     let mut r = BTreeSet::new();
 
     // We pull in all package ingo py paginating through
-    //     https://repology.org/api/v1/projects/?inrepo=nix_unstable&outdated=1
-    //     https://repology.org/api/v1/projects/${suffix}?inrepo=nix_unstable&outdated=1
-    let mut suffix: String = "".to_string();
+    //     ${url}?inrepo=${repo}&outdated=1
+    //     ${url}${suffix}?inrepo=${repo}&outdated=1
+    // where ${url} defaults to https://repology.org/api/v1/projects/
+    let mut suffix: String = start.to_string();
+    // Set when at least one of pages was fetched over network.
+    let mut fetched = false;
+    // Last project of the previous page. Each page must end past it.
+    let mut prev_last: Option<String> = None;
+    let (mut range_pages, mut projects): (usize, usize) = (0, 0);
 
     loop {
-        if cancel_fetch() {
+        range_pages += 1;
+        if range_pages > MAX_PAGES {
+            return Err(OldeError::RepologyPagination {
+                suffix,
+                reason: format!("more than {MAX_PAGES} pages"),
+            });
+        }
+        if ctx.is_canceled() {
             return Err(OldeError::Canceled(String::from("Repology fetch")));
         }
-        let url =
-            format!("https://repology.org/api/v1/projects/{suffix}?inrepo=nix_unstable&outdated=1");
-        // TODO: add an optional user identity string.
-        let user_agent = format!("{}/{} (+{})",
-            env!("CARGO_PKG_NAME"),
-            env!("CARGO_PKG_VERSION"),
-            "https://github.com/trofi/nix-olde");
-
-        log::debug!("Fetching from repology: {:?}", suffix);
-        let contents_u8 = run_cmd(&[
-            "curl",
-            "--user-agent", &user_agent,
-            &url])?;
-        // {
-        //   "python:networkx": [
-        //     {
-        //       "repo": "nix_unstable",
-        //       "visiblenamename": "python3.10-networkx",
-        //       "version": "2.8.6",
-        //       "status": "outdated",
-        //     },
-
-        #[derive(Deserialize, Debug)]
-        /// Dervivation description with subset of fields needed to detect outdated packages.
-        struct Repology {
-            repo: String,
-            visiblename: Option<String>,
-            version: Option<String>,
-            status: Option<String>,
-        }
-
-        let pkgs: BTreeMap<String, Vec<Repology>> = serde_json::from_slice(contents_u8.as_slice())?;
-
-        let mut next_suffix = suffix.clone();
-        for (n, vs) in &pkgs {
-            next_suffix = n.clone() + "/";
-
-            let olatest_entry = vs.iter().find_map(|e| {
-                if e.status == Some("newest".to_string()) || e.status == Some("unique".to_string()) {
-                    Some(e)
-                } else {
-                    None
-                }
-            });
-            let latest = match olatest_entry {
-                None => None,
-                Some (oe) => oe.version.clone(),
-            };
-
-            // There can be multiple nix_unstable package entries for a
-            // single repology entry: pycropto vs pycryptodome.
-            // Store all of them.
-            for v in vs {
-                if v.repo != "nix_unstable" {
-                    continue;
+        let page = pages.fetch_add(1, atomic::Ordering::Relaxed) + 1;
+        progress.update(&format!("page {page}"));
+
+        // Mirrors of '--repology-url' don't share entries.
+        let url_hash = short_hash(&opts.url);
+        let cache_key = match opts.all {
+            true => format!("repology-all-{repo}-{url_hash}-{suffix}"),
+            false => format!("repology-{repo}-{url_hash}-{suffix}"),
+        };
+        let cached = opts.cache.as_ref().and_then(|c| c.get(&cache_key));
+        let contents_u8 = match cached {
+            Some(c) => c,
+            None => {
+                // Repology asks to do no more than 1 request per second.
+                throttle.wait();
+                let c = fetch_page(opts, &suffix, ctx)?;
+                if let Some(cache) = &opts.cache {
+                    cache.put(&cache_key, &c)?;
                 }
+                fetched = true;
+                c
+            }
+        };
 
-                match &v.visiblename {
-                    None => {
-                        eprintln!("Skipping an entry without 'name' attribyte: {v:?}");
-                        log::debug!(
-                            "JSON for entry: {:?}",
-                            String::from_utf8(contents_u8.clone())
-                        );
-                        continue;
-                    }
-                    Some(vn) => {
-                        r.insert(Package {
-                            repology_name: n.clone(),
-                            name: vn.clone(),
-                            version: v.version.clone(),
-                            status: v.status.clone(),
-                            latest: latest.clone(),
-                        });
-                    }
+        let mut pkgs: Page = serde_json::from_slice(contents_u8.as_slice())?;
+        // Empty page is the end of the list.
+        let last = match pkgs.keys().next_back() {
+            None => break,
+            Some(l) => l.clone(),
+        };
+        // Pages start at 'suffix' project inclusive: the last page of
+        // the list consists of the previous page's last project only.
+        // A page ending before it would loop forever.
+        if let Some(pl) = &prev_last {
+            match last.cmp(pl) {
+                Ordering::Equal => break,
+                Ordering::Less => {
+                    return Err(OldeError::RepologyPagination {
+                        suffix,
+                        reason: format!("page ends at already seen project {last:?}"),
+                    })
                 }
+                Ordering::Greater => {}
             }
+            // Already counted and added with the previous page.
+            pkgs.remove(pl);
         }
-        if suffix == next_suffix {
+        // The rest belongs to the next range.
+        if let Some(e) = end {
+            pkgs.retain(|n, _| n.as_str() < e);
+        }
+        projects += pkgs.len();
+        add_page_packages(&mut r, &pkgs, opts);
+
+        if end.is_some_and(|e| last.as_str() >= e) {
             break;
         }
-        suffix = next_suffix;
+        suffix = last.clone() + "/";
+        prev_last = Some(last);
+    }
+    log::debug!("Fetched {range_pages} pages with {projects} projects starting at {start:?}");
+
+    Ok((r, projects, fetched))
+}
+
+/// Returns base URL of single project API:
+///     ${url}/../project/
+/// 'None' if 'opts.url' is not a projects API URL.
+fn single_project_url(opts: &FetchOptions) -> Option<String> {
+    opts.url
+        .strip_suffix("projects/")
+        .map(|base| format!("{base}project/"))
+}
+
+/// Fetches packages of a single 'name' project. Returns true along
+/// with packages if they were fetched over network.
+fn fetch_project(
+    opts: &FetchOptions,
+    project_url: &str,
+    name: &str,
+    throttle: &Throttle,
+    ctx: &CmdContext,
+) -> Result<(Vec<Repology>, bool), OldeError> {
+    let cache_key = format!("repology-project-{}-{name}", short_hash(&opts.url));
+    let cached = opts.cache.as_ref().and_then(|c| c.get(&cache_key));
+    let (contents_u8, fetched) = match cached {
+        Some(c) => (c, false),
+        None => {
+            throttle.wait();
+            let c = fetch_url(opts, &format!("{project_url}{name}"), ctx)?;
+            if let Some(cache) = &opts.cache {
+                cache.put(&cache_key, &c)?;
+            }
+            (c, true)
+        }
+    };
+
+    // A list of packages of a single project.
+    Ok((serde_json::from_slice(contents_u8.as_slice())?, fetched))
+}
+
+/// Fetches 'opts.only' projects one by one via single project API.
+fn fetch_projects(
+    opts: &FetchOptions,
+    project_url: &str,
+    progress: &mut TaskProgress,
+    ctx: &CmdContext,
+) -> Result<BTreeSet<Package>, OldeError> {
+    let throttle = Throttle::new(opts.min_fetch_interval);
+    let mut r = BTreeSet::new();
+    let mut fetched = false;
+
+    for name in &opts.only {
+        if ctx.is_canceled() {
+            return Err(OldeError::Canceled(String::from("Repology fetch")));
+        }
+        progress.update(name);
+
+        let (pkgs, f) = fetch_project(opts, project_url, name, &throttle, ctx)?;
+        fetched |= f;
+        let page: Page = BTreeMap::from([(name.clone(), pkgs)]);
+        add_page_packages(&mut r, &page, opts);
+    }
+
+    let note = format!("{} projects", opts.only.len());
+    match fetched {
+        true => progress.set_note(&note),
+        false => progress.set_note(&format!("{note}, cached")),
+    }
+    Ok(r)
+}
+
+/// Returns list of all outdated derivations according to repology.
+pub(crate) fn get_packages(
+    opts: &FetchOptions,
+    progress: &mut TaskProgress,
+    ctx: &CmdContext,
+) -> Result<BTreeSet<Package>, OldeError> {
+    if let Some(dump) = &opts.dump {
+        progress.set_note("dump");
+        let mut r = read_dump(dump, opts)?;
+        if !opts.only.is_empty() {
+            r.retain(|p| opts.only.contains(&p.repology_name));
+        }
+        return Ok(r);
+    }
+
+    if !opts.only.is_empty() {
+        match single_project_url(opts) {
+            Some(url) => return fetch_projects(opts, &url, progress, ctx),
+            None => log::info!("No single project API at {}. Fetching all.", opts.url),
+        }
+    }
+
+    let throttle = Throttle::new(opts.min_fetch_interval);
+    let pages = AtomicUsize::new(0);
+    let starts = shard_starts(opts.concurrency);
+    let shared_progress: &TaskProgress = progress;
+    let results: Vec<_> = std::thread::scope(|s| {
+        let handles: Vec<_> = starts
+            .iter()
+            .enumerate()
+            .map(|(i, start)| {
+                let end = starts.get(i + 1).map(|e| e.as_str());
+                let (throttle, pages) = (&throttle, &pages);
+                s.spawn(move || {
+                    fetch_range(opts, start, end, throttle, pages, shared_progress, ctx)
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| h.join().expect("repology fetch thread"))
+            .collect()
+    });
+
+    let mut r = BTreeSet::new();
+    let mut fetched = false;
+    let mut projects: usize = 0;
+    for res in results {
+        let (ps, n, f) = res?;
+        r.extend(ps);
+        projects += n;
+        fetched |= f;
+    }
+
+    // Helps to spot runs that stopped paginating early.
+    let pages = pages.into_inner();
+    let note = format!("{projects} projects over {pages} pages");
+    match fetched {
+        true => progress.set_note(&note),
+        false => progress.set_note(&format!("{note}, cached")),
+    }
+    if !opts.only.is_empty() {
+        r.retain(|p| opts.only.contains(&p.repology_name));
     }
 
     Ok(r)
 }
+
+/// Returns maintainers of 'opts.repo' packages of each of 'projects'.
+/// Each project takes an extra single project API fetch. Failed
+/// fetches are skipped.
+pub(crate) fn get_maintainers(
+    opts: &FetchOptions,
+    projects: &[&str],
+    ctx: &CmdContext,
+) -> Result<BTreeMap<String, BTreeSet<String>>, OldeError> {
+    let project_url = match single_project_url(opts) {
+        Some(url) => url,
+        None => {
+            log::warn!(
+                "No single project API at {}. Skipping maintainers.",
+                opts.url
+            );
+            return Ok(BTreeMap::new());
+        }
+    };
+
+    let throttle = Throttle::new(opts.min_fetch_interval);
+    let mut r = BTreeMap::new();
+    for name in projects {
+        if ctx.is_canceled() {
+            return Err(OldeError::Canceled(String::from(
+                "Repology maintainers fetch",
+            )));
+        }
+        let pkgs = match fetch_project(opts, &project_url, name, &throttle, ctx) {
+            Ok((pkgs, _)) => pkgs,
+            Err(e) => {
+                log::warn!("Failed to fetch maintainers of {name:?}: {e}");
+                continue;
+            }
+        };
+        let ms: BTreeSet<String> = pkgs
+            .into_iter()
+            .filter(|p| p.repo == opts.repo)
+            .flat_map(|p| p.maintainers)
+            .collect();
+        r.insert(name.to_string(), ms);
+    }
+    Ok(r)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_delay_doubles() {
+        let interval = Duration::from_secs(2);
+        assert_eq!(retry_delay(interval, 0), Duration::from_secs(2));
+        assert_eq!(retry_delay(interval, 1), Duration::from_secs(4));
+        assert_eq!(retry_delay(interval, 2), Duration::from_secs(8));
+    }
+
+    #[test]
+    fn retry_delay_without_throttling() {
+        assert_eq!(retry_delay(Duration::ZERO, 0), MIN_RETRY_DELAY);
+        assert_eq!(retry_delay(Duration::ZERO, 1), MIN_RETRY_DELAY * 2);
+    }
+
+    /// Unreachable API: pages missing in the cache fail to fetch.
+    const TEST_URL: &str = "http://127.0.0.1:9/api/v1/projects/";
+
+    fn options(cache: Option<Cache>) -> FetchOptions {
+        FetchOptions {
+            url: TEST_URL.to_string(),
+            repo: "nix_unstable".to_string(),
+            retries: 0,
+            dump: None,
+            cache,
+            min_fetch_interval: Duration::ZERO,
+            concurrency: 1,
+            only: Vec::new(),
+            all: false,
+            pre_release: false,
+            http: http::HttpOptions::new(None, String::from("curl"), Vec::new()),
+            user_agent_suffix: None,
+        }
+    }
+
+    fn entries(json: &str) -> Vec<Repology> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn latest_version_skips_pre_releases() {
+        let vs = entries(
+            r#"[{"repo": "arch", "version": "2.0-rc1", "status": "newest"},
+                {"repo": "debian", "version": "1.9", "status": "legacy"},
+                {"repo": "nix_unstable", "version": "1.8", "status": "outdated"}]"#,
+        );
+        let mut opts = options(None);
+        assert_eq!(latest_version("p", &vs, &opts).as_deref(), Some("1.9"));
+        opts.pre_release = true;
+        assert_eq!(latest_version("p", &vs, &opts).as_deref(), Some("2.0-rc1"));
+    }
+
+    #[test]
+    fn latest_version_keeps_installed_pre_release() {
+        let vs = entries(
+            r#"[{"repo": "arch", "version": "2.0-rc1", "status": "newest"},
+                {"repo": "nix_unstable", "version": "2.0-rc1", "status": "newest"},
+                {"repo": "debian", "version": "1.9", "status": "legacy"}]"#,
+        );
+        assert_eq!(
+            latest_version("p", &vs, &options(None)).as_deref(),
+            Some("2.0-rc1")
+        );
+        // Without stable versions pre-release is still the latest one.
+        let vs = entries(r#"[{"repo": "arch", "version": "1.0beta1", "status": "unique"}]"#);
+        assert_eq!(
+            latest_version("p", &vs, &options(None)).as_deref(),
+            Some("1.0beta1")
+        );
+    }
+
+    /// Returns a page of projects with a single 'nix_unstable' package
+    /// each.
+    fn page(projects: &[&str]) -> String {
+        let entries: Vec<String> = projects
+            .iter()
+            .map(|p| format!(r#""{p}": [{{"repo": "nix_unstable", "version": "1.0"}}]"#))
+            .collect();
+        format!("{{{}}}", entries.join(", "))
+    }
+
+    /// Fetches ['start'; 'end') range of 'pages' keyed by page suffix.
+    fn fetch_cached_range(
+        name: &str,
+        pages: &[(&str, &[&str])],
+        start: &str,
+        end: Option<&str>,
+    ) -> Result<(BTreeSet<Package>, usize, bool), OldeError> {
+        let dir = std::env::temp_dir().join(format!("nix-olde-{name}-{}", std::process::id()));
+        let cache = Cache::new(dir.to_str().unwrap(), Duration::from_secs(3600));
+        for (suffix, projects) in pages {
+            let key = format!("repology-nix_unstable-{}-{suffix}", short_hash(TEST_URL));
+            cache.put(&key, page(projects).as_bytes()).unwrap();
+        }
+        let r = fetch_range(
+            &options(Some(cache)),
+            start,
+            end,
+            &Throttle::new(Duration::ZERO),
+            &AtomicUsize::new(0),
+            &TaskProgress::new("test", true),
+            &CmdContext::new(false, None),
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+        r
+    }
+
+    #[test]
+    fn fetches_pages_until_last_project_repeats() {
+        // Each page starts at the last project of the previous one.
+        let pages: &[(&str, &[&str])] = &[("", &["a", "b"]), ("b/", &["b", "c"]), ("c/", &["c"])];
+        let (ps, projects, fetched) = fetch_cached_range("pages", pages, "", None).unwrap();
+        let names: Vec<&str> = ps.iter().map(|p| p.repology_name.as_str()).collect();
+        assert_eq!(names, ["a", "b", "c"]);
+        assert_eq!(projects, 3);
+        assert!(!fetched);
+    }
+
+    #[test]
+    fn fetches_pages_until_empty_one() {
+        let pages: &[(&str, &[&str])] = &[("", &["a", "b"]), ("b/", &[])];
+        let (ps, projects, _) = fetch_cached_range("empty", pages, "", None).unwrap();
+        assert_eq!(ps.len(), 2);
+        assert_eq!(projects, 2);
+    }
+
+    #[test]
+    fn stops_at_range_end() {
+        let pages: &[(&str, &[&str])] = &[("", &["a", "b"]), ("b/", &["b", "c", "d"])];
+        let (ps, projects, _) = fetch_cached_range("range", pages, "", Some("c")).unwrap();
+        let names: Vec<&str> = ps.iter().map(|p| p.repology_name.as_str()).collect();
+        assert_eq!(names, ["a", "b"]);
+        assert_eq!(projects, 2);
+    }
+
+    #[test]
+    fn rejects_page_going_backwards() {
+        let pages: &[(&str, &[&str])] = &[("", &["a", "b", "c"]), ("c/", &["a", "b"])];
+        match fetch_cached_range("stuck", pages, "", None) {
+            Err(OldeError::RepologyPagination { suffix, reason }) => {
+                assert_eq!(suffix, "c/");
+                assert!(reason.contains("already seen"), "{reason}");
+            }
+            Err(e) => panic!("unexpected error: {e}"),
+            Ok(_) => panic!("page going backwards is accepted"),
+        }
+    }
+}
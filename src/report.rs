@@ -0,0 +1,168 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::error::*;
+use crate::version::*;
+
+/// Installed version relative to the latest one.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    /// Installed version is older than the latest one.
+    Outdated,
+    /// Installed version is newer than the latest one.
+    Ahead,
+    /// Installed version is the latest one. Reported only with
+    /// 'show_all'.
+    Current,
+}
+
+impl State {
+    /// Returns the same name as used in JSON records.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            State::Outdated => "outdated",
+            State::Ahead => "ahead",
+            State::Current => "current",
+        }
+    }
+}
+
+/// Where the latest version of a package comes from.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    /// Latest version known to repology.
+    #[default]
+    Repology,
+    /// Version in a reference nixpkgs of 'compare_nixpkgs'.
+    Reference,
+}
+
+impl Source {
+    /// Returns the same name as used in JSON records.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Source::Repology => "repology",
+            Source::Reference => "reference",
+        }
+    }
+}
+
+/// Installed version of a single nixpkgs attribute.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InstalledVersion {
+    pub attribute: String,
+    pub version: String,
+}
+
+/// Outdated package record as emitted by all output formats.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OutdatedPackage {
+    /// Repology project name.
+    pub repology_name: String,
+    /// nixpkgs attributes of the package.
+    pub attribute: BTreeSet<String>,
+    /// Latest version known to repology.
+    pub repology_version: Option<String>,
+    /// Installed versions.
+    pub nixpkgs_version: BTreeSet<String>,
+    /// Installed versions paired with their attributes.
+    pub installed: Vec<InstalledVersion>,
+    /// Gap between the most recent installed version and the latest one.
+    pub severity: Severity,
+    /// Repology statuses of nixpkgs packages: 'outdated', 'legacy', etc.
+    pub status: BTreeSet<String>,
+    /// Whether installed version is behind or ahead of the latest one.
+    pub state: State,
+    /// Source of 'repology_version'. Reports of older versions lack
+    /// it: they always compare against repology.
+    #[serde(default)]
+    pub source: Source,
+    /// Repology web page of the project. Unset with '--compare-nixpkgs'
+    /// or without 'repology_urls'.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repology_url: Option<String>,
+    /// Largest closure size in bytes among installed store paths of
+    /// the package. Set only with 'with_size' for built packages.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub closure_size_bytes: Option<u64>,
+    /// Whether installed version is the latest one. Set only with
+    /// 'show_all'.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub up_to_date: Option<bool>,
+    /// Repology maintainers of nixpkgs packages of the project. Set
+    /// only with 'with_maintainers'.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maintainers: Option<BTreeSet<String>>,
+}
+
+impl OutdatedPackage {
+    /// Returns true unless the package is reported only due to
+    /// 'show_all'.
+    pub fn is_outdated(&self) -> bool {
+        self.state != State::Current
+    }
+}
+
+/// Available package not found on repology. Usually a rename or a
+/// newly added package.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MissingRepology {
+    /// nixpkgs attribute path.
+    pub attribute: String,
+    /// 'pname' used to look the package up on repology.
+    pub pname: String,
+    /// Installed package name.
+    pub name: String,
+}
+
+/// nixpkgs package outdated according to repology.
+#[derive(Serialize, Debug)]
+pub struct RepologyPackage {
+    /// Repology project name.
+    pub repology_name: String,
+    /// nixpkgs package name as seen by repology.
+    pub name: String,
+    /// nixpkgs version.
+    pub version: Option<String>,
+    /// Latest version known to repology.
+    pub latest: Option<String>,
+}
+
+/// Result of a 'run()'.
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Outdated packages ordered by repology name.
+    pub outdated: Vec<OutdatedPackage>,
+    /// Number of installed packages matching 'attr_prefix'.
+    pub installed: usize,
+    /// Number of outdated packages hidden by 'ignore_file' and 'exclude'.
+    pub suppressed: usize,
+    /// Installed packages not found in available packages.
+    pub missing_available: Vec<String>,
+    /// Available packages not found on repology. Only accurate with
+    /// 'all_repology_projects': otherwise up to date packages are
+    /// missing as well.
+    pub missing_repology: Vec<MissingRepology>,
+    /// Installed nixpkgs attributes of every matched repology project,
+    /// outdated or not.
+    pub attributes: BTreeMap<String, BTreeSet<String>>,
+    /// Outdated nixpkgs packages with 'repology_only'. Empty otherwise.
+    pub repology: Vec<RepologyPackage>,
+    /// Repology repository packages were compared against.
+    pub repology_repo: String,
+    /// Repology repository matching system's nixpkgs channel if
+    /// detected.
+    pub detected_repo: Option<String>,
+    /// Git revision of system's nixpkgs if known.
+    pub nixpkgs_revision: Option<String>,
+    /// Data sources that failed with 'best_effort' along with their
+    /// errors. Results depending on them are empty.
+    pub unavailable: Vec<(&'static str, OldeError)>,
+    /// Durations of 'repology', 'installed' and 'available' tasks.
+    pub timings: BTreeMap<&'static str, Duration>,
+}
@@ -0,0 +1,265 @@
+use clap::ValueEnum;
+use serde_derive::{Deserialize, Serialize};
+
+/// Size of a gap between two versions.
+#[derive(ValueEnum, Serialize, Deserialize, Eq, PartialEq, Ord, PartialOrd, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// At least one of versions is not a dot-separated list of numbers.
+    #[value(skip)]
+    Unknown,
+    Patch,
+    Minor,
+    Major,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Unknown => "unknown",
+            Severity::Patch => "patch",
+            Severity::Minor => "minor",
+            Severity::Major => "major",
+        }
+    }
+}
+
+/// Normalizes version spelling for equality checks: "V1.2.0" and
+/// "1.2" both become "1.2". Rules:
+/// - surrounding whitespace is dropped
+/// - letters are lowercased
+/// - leading 'v' before a digit is dropped
+/// - trailing '.0' components are dropped (but the first one)
+pub(crate) fn normalize(v: &str) -> String {
+    let v = v.trim().to_lowercase();
+    let mut v = match v.strip_prefix('v') {
+        Some(rest) if rest.starts_with(|c: char| c.is_ascii_digit()) => rest.to_string(),
+        _ => v,
+    };
+    while let Some(rest) = v.strip_suffix(".0") {
+        if rest.is_empty() || rest.ends_with('.') {
+            break;
+        }
+        v.truncate(rest.len());
+    }
+    v
+}
+
+/// Returns true for pre-release versions like "2.0.0-rc1", "2.0beta2"
+/// or "3.1.dev4". Single letter suffixes are ambiguous and are
+/// considered stable: "1.1.1a", "2.0b".
+pub(crate) fn is_pre_release(v: &str) -> bool {
+    const MARKERS: &[&str] = &["alpha", "beta", "rc", "pre", "preview", "dev"];
+    let v = v.to_lowercase();
+    v.split(|c: char| !c.is_ascii_alphabetic())
+        .any(|w| MARKERS.contains(&w))
+}
+
+/// Parses semver-ish "1.2.3" into numeric components.
+fn parse_numeric(v: &str) -> Option<Vec<u64>> {
+    v.split('.').map(|c| c.parse::<u64>().ok()).collect()
+}
+
+/// Compares two dot-separated numeric versions: 1.2 == 1.2.0 < 1.10.
+/// 'None' if any of them is not numeric.
+pub(crate) fn compare(a: &str, b: &str) -> Option<std::cmp::Ordering> {
+    let (mut a, mut b) = (parse_numeric(a)?, parse_numeric(b)?);
+    let len = a.len().max(b.len());
+    a.resize(len, 0);
+    b.resize(len, 0);
+    Some(a.cmp(&b))
+}
+
+/// Splits 'v' into components the way 'builtins.compareVersions' does:
+/// runs of digits or of other characters separated by '.' or '-'.
+fn nix_components(v: &str) -> impl Iterator<Item = &str> {
+    let mut rest = v;
+    std::iter::from_fn(move || {
+        rest = rest.trim_start_matches(['.', '-']);
+        if rest.is_empty() {
+            return None;
+        }
+        let digits = rest.starts_with(|c: char| c.is_ascii_digit());
+        let end = rest
+            .find(|c: char| c == '.' || c == '-' || c.is_ascii_digit() != digits)
+            .unwrap_or(rest.len());
+        let (c, r) = rest.split_at(end);
+        rest = r;
+        Some(c)
+    })
+}
+
+/// Returns true if component 'a' sorts before 'b' in
+/// 'builtins.compareVersions'. Missing components are empty.
+fn nix_component_lt(a: &str, b: &str) -> bool {
+    match (a.parse::<u64>().ok(), b.parse::<u64>().ok()) {
+        (Some(x), Some(y)) => x < y,
+        _ if a.is_empty() && b.starts_with(|c: char| c.is_ascii_digit()) => true,
+        _ if a == "pre" && b != "pre" => true,
+        _ if b == "pre" => false,
+        // '2.3a' < '2.3.1'.
+        (_, Some(_)) => true,
+        (Some(_), _) => false,
+        _ => a < b,
+    }
+}
+
+/// Compares any two versions the way 'builtins.compareVersions' does:
+/// 1.2 < 1.2.1 < 1.10, 1.0pre1 < 1.0, 2024-01-05 < 2024-02-01. Used
+/// for versions of two nixpkgs where nix ordering is authoritative.
+pub(crate) fn compare_nix(a: &str, b: &str) -> std::cmp::Ordering {
+    let (mut ac, mut bc) = (nix_components(a), nix_components(b));
+    loop {
+        let (x, y) = match (ac.next(), bc.next()) {
+            (None, None) => return std::cmp::Ordering::Equal,
+            (x, y) => (x.unwrap_or(""), y.unwrap_or("")),
+        };
+        if nix_component_lt(x, y) {
+            return std::cmp::Ordering::Less;
+        }
+        if nix_component_lt(y, x) {
+            return std::cmp::Ordering::Greater;
+        }
+    }
+}
+
+/// Returns true if both versions have the same first numeric
+/// component: "2.8.6" and "2.10" do, "2.8.6" and "3.0" or "unstable"
+/// do not.
+pub(crate) fn same_major(a: &str, b: &str) -> bool {
+    let major = |v: &str| v.split('.').next().and_then(|c| c.parse::<u64>().ok());
+    major(a).is_some_and(|m| Some(m) == major(b))
+}
+
+/// Classifies a gap between 'current' and 'latest' versions by the
+/// first differing component: 1.2.3 -> 2.0.0 is a 'major' gap.
+pub(crate) fn severity(current: &str, latest: &str) -> Severity {
+    let (c, l) = match (parse_numeric(current), parse_numeric(latest)) {
+        (Some(c), Some(l)) => (c, l),
+        _ => return Severity::Unknown,
+    };
+    let get = |v: &Vec<u64>, i: usize| v.get(i).copied().unwrap_or(0);
+    if get(&c, 0) != get(&l, 0) {
+        Severity::Major
+    } else if get(&c, 1) != get(&l, 1) {
+        Severity::Minor
+    } else {
+        Severity::Patch
+    }
+}
+
+/// Classifies a gap between the most recent of installed 'versions'
+/// and 'latest' version. Non-numeric installed versions are ignored.
+pub(crate) fn severity_of_set<'a>(
+    versions: impl IntoIterator<Item = &'a str>,
+    latest: Option<&str>,
+) -> Severity {
+    let latest = match latest {
+        None => return Severity::Unknown,
+        Some(l) => l,
+    };
+    match newest_numeric(versions) {
+        None => Severity::Unknown,
+        Some(n) => severity(n, latest),
+    }
+}
+
+/// Returns the most recent of numeric 'versions'. Non-numeric versions
+/// are ignored.
+pub(crate) fn newest_numeric<'a>(versions: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    versions
+        .into_iter()
+        .filter_map(|v| Some((parse_numeric(v)?, v)))
+        .max()
+        .map(|(_, v)| v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cmp::Ordering::*;
+
+    #[test]
+    fn severity_is_the_first_differing_component() {
+        assert_eq!(severity("1.2.3", "2.0.0"), Severity::Major);
+        assert_eq!(severity("1.2.3", "1.3"), Severity::Minor);
+        assert_eq!(severity("1.2.3", "1.2.4"), Severity::Patch);
+        assert_eq!(severity("1.2", "1.2.0.1"), Severity::Patch);
+        assert_eq!(severity("1.2", "1.2-rc1"), Severity::Unknown);
+        assert_eq!(severity("unstable-2024-01-01", "1.0"), Severity::Unknown);
+    }
+
+    #[test]
+    fn severity_of_set_uses_the_newest_numeric_version() {
+        assert_eq!(
+            severity_of_set(["1.2.3", "2.1", "git"], Some("2.2")),
+            Severity::Minor
+        );
+        assert_eq!(severity_of_set(["git"], Some("2.2")), Severity::Unknown);
+        assert_eq!(severity_of_set(["1.0"], None), Severity::Unknown);
+    }
+
+    #[test]
+    fn compare_is_numeric_only() {
+        assert_eq!(compare("1.2", "1.2.0"), Some(Equal));
+        assert_eq!(compare("1.9", "1.10"), Some(Less));
+        assert_eq!(compare("2.0.1", "2"), Some(Greater));
+        assert_eq!(compare("1.2-rc1", "1.2"), None);
+        assert_eq!(compare("1.2", "unstable"), None);
+    }
+
+    #[test]
+    fn newest_numeric_skips_non_numeric_versions() {
+        assert_eq!(
+            newest_numeric(["1.9", "1.10", "2.0-rc1", "git"]),
+            Some("1.10")
+        );
+        assert_eq!(newest_numeric(["git", "unstable"]), None);
+    }
+
+    #[test]
+    fn normalize_drops_spelling_differences() {
+        assert_eq!(normalize(" V1.2.0 "), "1.2");
+        assert_eq!(normalize("v1.2"), "1.2");
+        assert_eq!(normalize("2.0"), "2");
+        assert_eq!(normalize("1.0.0"), "1");
+        assert_eq!(normalize("0"), "0");
+        assert_eq!(normalize("1.10"), "1.10");
+        assert_eq!(normalize("very-1.0"), "very-1");
+        assert_eq!(normalize("1.0-RC1"), "1.0-rc1");
+    }
+
+    #[test]
+    fn same_major_needs_numeric_major() {
+        assert!(same_major("2.8.6", "2.10"));
+        assert!(!same_major("2.8.6", "3.0"));
+        assert!(!same_major("unstable", "unstable"));
+        assert!(!same_major("2.8.6", "unstable-2024-01-01"));
+    }
+
+    #[test]
+    fn pre_release_needs_a_marker_word() {
+        assert!(is_pre_release("2.0.0-rc1"));
+        assert!(is_pre_release("2.0beta2"));
+        assert!(is_pre_release("3.1.dev4"));
+        assert!(is_pre_release("1.0-PRE"));
+        assert!(!is_pre_release("1.1.1a"));
+        assert!(!is_pre_release("2.0b"));
+        assert!(!is_pre_release("1.2.3"));
+        assert!(!is_pre_release("unstable-2024-01-01"));
+    }
+
+    #[test]
+    fn compare_nix_orders_like_nix() {
+        assert_eq!(compare_nix("1.2", "1.2.0"), Less);
+        assert_eq!(compare_nix("1.2.1", "1.10"), Less);
+        assert_eq!(compare_nix("1.0pre1", "1.0"), Less);
+        assert_eq!(compare_nix("2.3a", "2.3.1"), Less);
+        assert_eq!(compare_nix("2.3", "2.3a"), Less);
+        assert_eq!(
+            compare_nix("unstable-2024-05-01", "unstable-2024-01-01"),
+            Greater
+        );
+        assert_eq!(compare_nix("1.2-3", "1.2.3"), Equal);
+    }
+}